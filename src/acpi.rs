@@ -1,6 +1,6 @@
 use core::ptr::NonNull;
 
-use acpi::{AcpiTables, InterruptModel};
+use acpi::{AcpiTables, InterruptModel, PlatformInfo};
 use x86_64::PhysAddr;
 
 use crate::{memory, println};
@@ -27,9 +27,15 @@ impl acpi::AcpiHandler for Handler {
     fn unmap_physical_region<T>(_region: &acpi::PhysicalMapping<Self, T>) {}
 }
 
+/// Finds the ACPI tables through the BIOS RSDP and parses the platform
+/// information out of them, like the MADT's interrupt model.
+pub fn platform_info() -> Option<PlatformInfo> {
+    let tables = unsafe { AcpiTables::search_for_rsdp_bios(Handler).ok()? };
+    tables.platform_info().ok()
+}
+
 pub fn read_acpi() {
-    let table = unsafe { AcpiTables::search_for_rsdp_bios(Handler).unwrap() };
-    let info = table.platform_info().unwrap();
+    let info = platform_info().unwrap();
     if let InterruptModel::Apic(apic) = info.interrupt_model {
         println!("{:?}", apic);
     } else {
@@ -0,0 +1,239 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::{self, NonNull},
+};
+
+use alloc::{collections::TryReserveError, vec::Vec};
+use linked_list_allocator::Heap;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use crate::memory;
+
+#[cfg(feature = "slab_allocator")]
+use self::slab::SlabAllocator;
+use self::stats::Tracked;
+
+pub mod slab;
+pub mod stats;
+
+pub const HEAP_START: usize = 0x_4444_4444_0000;
+/// Size of the region mapped up front by `init_heap`.
+pub const HEAP_SIZE: usize = 1024 * 1024;
+/// Size the heap may grow to once the initial region is exhausted.
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024;
+/// Minimum number of bytes mapped each time the heap grows.
+const HEAP_GROWTH_STEP: usize = 64 * 4096;
+
+pub fn init_heap(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let page_range = {
+        let heap_start = VirtAddr::new(HEAP_START as u64);
+        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_start_page = Page::containing_address(heap_start);
+        let heap_end_page = Page::containing_address(heap_end);
+        Page::range_inclusive(heap_start_page, heap_end_page)
+    };
+
+    for page in page_range {
+        map_heap_page(page, mapper, frame_allocator)?;
+    }
+
+    unsafe {
+        heap().init(HEAP_START as *mut u8, HEAP_SIZE);
+    }
+
+    Ok(())
+}
+
+fn map_heap_page(
+    page: Page,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    Ok(())
+}
+
+/// A linked list heap that maps more pages when an allocation doesn't fit.
+///
+/// New pages are taken from `memory::MAPPER` and `memory::FRAME_ALLOCATOR`,
+/// after swapping out lazy pages if frames are short, so code holding either
+/// of those locks, the lazy regions or the swap space must not allocate.
+pub struct GrowableHeap {
+    heap: Mutex<Heap>,
+    max_size: usize,
+}
+
+impl GrowableHeap {
+    pub const fn new(max_size: usize) -> Self {
+        Self {
+            heap: Mutex::new(Heap::empty()),
+            max_size,
+        }
+    }
+
+    pub unsafe fn init(&self, heap_bottom: *mut u8, heap_size: usize) {
+        self.heap.lock().init(heap_bottom, heap_size);
+    }
+
+    /// Number of bytes currently mapped for the heap.
+    pub fn size(&self) -> usize {
+        self.heap.lock().top() as usize - HEAP_START
+    }
+
+    /// Number of mapped bytes not handed out, however fragmented.
+    pub fn free(&self) -> usize {
+        self.heap.lock().free()
+    }
+
+    /// Maps at least `min_size` bytes past the current top of the heap.
+    fn grow(&self, heap: &mut Heap, min_size: usize) -> bool {
+        let mapped = heap.top() as usize - HEAP_START;
+        let wanted = (min_size.max(HEAP_GROWTH_STEP) + 4095) & !4095;
+        let size = wanted.min(self.max_size.saturating_sub(mapped));
+        if size < min_size {
+            return false;
+        }
+
+        let (Some(mapper), Some(frame_allocator)) =
+            (memory::MAPPER.get(), memory::FRAME_ALLOCATOR.get())
+        else {
+            return false;
+        };
+        // a short mapping is fine, so it doesn't matter if this falls short
+        memory::lazy::reserve_frames(size / 4096);
+        let mut mapper = mapper.lock();
+        let mut frame_allocator = frame_allocator.lock();
+
+        let top = Page::containing_address(VirtAddr::from_ptr(heap.top()));
+        let mut grown = 0;
+        for page in Page::range(top, top + (size / 4096) as u64) {
+            if map_heap_page(page, &mut *mapper, &mut *frame_allocator).is_err() {
+                break;
+            }
+            grown += 4096;
+        }
+
+        // pages that did get mapped belong to the heap either way
+        if grown > 0 {
+            unsafe { heap.extend(grown) };
+        }
+        grown >= min_size
+    }
+}
+
+unsafe impl GlobalAlloc for GrowableHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.heap.lock();
+        if let Ok(ptr) = heap.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+
+        if self.grow(&mut heap, layout.size() + layout.align()) {
+            if let Ok(ptr) = heap.allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+        }
+        ptr::null_mut()
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap
+            .lock()
+            .deallocate(NonNull::new_unchecked(ptr), layout);
+    }
+}
+
+#[cfg(feature = "slab_allocator")]
+#[global_allocator]
+pub static ALLOCATOR: Tracked<SlabAllocator> =
+    Tracked::new(SlabAllocator::new(GrowableHeap::new(HEAP_MAX_SIZE)));
+
+#[cfg(not(feature = "slab_allocator"))]
+#[global_allocator]
+pub static ALLOCATOR: Tracked<GrowableHeap> = Tracked::new(GrowableHeap::new(HEAP_MAX_SIZE));
+
+/// The linked list heap behind the global allocator.
+#[cfg(feature = "slab_allocator")]
+pub fn heap() -> &'static GrowableHeap {
+    ALLOCATOR.inner().fallback()
+}
+
+/// The linked list heap behind the global allocator.
+#[cfg(not(feature = "slab_allocator"))]
+pub fn heap() -> &'static GrowableHeap {
+    ALLOCATOR.inner()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub allocated: usize,
+    pub peak: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    pub failures: usize,
+    /// Bytes currently mapped for the heap.
+    pub size: usize,
+    pub max_size: usize,
+    /// Mapped bytes not handed out. The largest free block isn't known, as
+    /// `linked_list_allocator` doesn't expose its holes.
+    pub free: usize,
+}
+
+pub fn stats() -> HeapStats {
+    let counters = ALLOCATOR.counters();
+    HeapStats {
+        allocated: counters.allocated,
+        peak: counters.peak,
+        allocations: counters.allocations,
+        frees: counters.frees,
+        failures: counters.failures,
+        size: heap().size(),
+        max_size: HEAP_MAX_SIZE,
+        free: heap().free(),
+    }
+}
+
+/// Builds a vector of `len` copies of `value`, returning an error instead of
+/// aborting when the heap can't hold it.
+pub fn try_vec<T: Clone>(value: T, len: usize) -> Result<Vec<T>, TryReserveError> {
+    let mut vec = Vec::new();
+    vec.try_reserve_exact(len)?;
+    vec.resize(len, value);
+    Ok(vec)
+}
+
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    let stats = stats();
+    panic!(
+        "out of memory: allocation of {} bytes (align {}) failed\n\
+         heap: {} of {} bytes mapped, {} allocated, {} free, {} failures",
+        layout.size(),
+        layout.align(),
+        stats.size,
+        stats.max_size,
+        stats.allocated,
+        stats.free,
+        stats.failures
+    )
+}
+
+#[test_case]
+fn test_try_vec_reports_failure() {
+    assert_eq!(try_vec(7u8, 16).unwrap(), [7; 16]);
+    assert!(try_vec(0u8, HEAP_MAX_SIZE * 2).is_err());
+    assert!(stats().failures > 0);
+}
@@ -0,0 +1,116 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr,
+};
+
+use spin::Mutex;
+
+use super::GrowableHeap;
+
+/// Block sizes served from the per-class free lists.
+///
+/// Each size is a power of two and also used as the block alignment.
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Size of the chunk requested from the fallback heap when a free list is empty.
+const SLAB_SIZE: usize = 4096;
+
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+/// A fixed-size-block allocator with one free list per size class.
+///
+/// Small allocations are rounded up to the next block size and served from
+/// that class' free list, refilled one slab at a time. Anything larger than
+/// the biggest block size goes straight to the fallback heap. Freed blocks
+/// return to their free list and are never handed back to the fallback.
+pub struct SlabAllocator {
+    list_heads: Mutex<[Option<&'static mut ListNode>; BLOCK_SIZES.len()]>,
+    fallback: GrowableHeap,
+}
+
+impl SlabAllocator {
+    pub const fn new(fallback: GrowableHeap) -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        Self {
+            list_heads: Mutex::new([EMPTY; BLOCK_SIZES.len()]),
+            fallback,
+        }
+    }
+
+    pub fn fallback(&self) -> &GrowableHeap {
+        &self.fallback
+    }
+
+    /// Carves a new slab into blocks of class `index`, returning one of them.
+    unsafe fn refill(&self, index: usize) -> *mut u8 {
+        let block_size = BLOCK_SIZES[index];
+        let layout = Layout::from_size_align(SLAB_SIZE, block_size).unwrap();
+        let slab = self.fallback.alloc(layout);
+        if slab.is_null() {
+            return ptr::null_mut();
+        }
+
+        let mut list_heads = self.list_heads.lock();
+        for offset in (block_size..SLAB_SIZE).step_by(block_size) {
+            let node = slab.add(offset) as *mut ListNode;
+            node.write(ListNode {
+                next: list_heads[index].take(),
+            });
+            list_heads[index] = Some(&mut *node);
+        }
+        slab
+    }
+}
+
+/// Returns the index of the smallest size class that fits `layout`.
+fn size_class(layout: &Layout) -> Option<usize> {
+    let required = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&size| size >= required)
+}
+
+unsafe impl GlobalAlloc for SlabAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(index) = size_class(&layout) else {
+            return self.fallback.alloc(layout);
+        };
+
+        let mut list_heads = self.list_heads.lock();
+        match list_heads[index].take() {
+            Some(node) => {
+                list_heads[index] = node.next.take();
+                node as *mut ListNode as *mut u8
+            }
+            None => {
+                drop(list_heads);
+                self.refill(index)
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let Some(index) = size_class(&layout) else {
+            return self.fallback.dealloc(ptr, layout);
+        };
+
+        let mut list_heads = self.list_heads.lock();
+        let node = ptr as *mut ListNode;
+        node.write(ListNode {
+            next: list_heads[index].take(),
+        });
+        list_heads[index] = Some(&mut *node);
+    }
+}
+
+#[cfg(feature = "slab_allocator")]
+#[test_case]
+fn test_block_reuse() {
+    use alloc::boxed::Box;
+
+    let first = Box::into_raw(Box::new(1u64));
+    unsafe { drop(Box::from_raw(first)) };
+    let second = Box::into_raw(Box::new(2u64));
+    assert_eq!(first, second);
+    unsafe { drop(Box::from_raw(second)) };
+}
@@ -0,0 +1,75 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Wraps an allocator and counts everything that goes through it.
+pub struct Tracked<A> {
+    inner: A,
+    allocated: AtomicUsize,
+    peak: AtomicUsize,
+    allocations: AtomicUsize,
+    frees: AtomicUsize,
+    failures: AtomicUsize,
+}
+
+/// A snapshot of the counters kept by `Tracked`.
+#[derive(Debug, Clone, Copy)]
+pub struct AllocCounters {
+    /// Bytes currently handed out to callers.
+    pub allocated: usize,
+    /// Highest value `allocated` has reached.
+    pub peak: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    /// Allocations the inner allocator could not satisfy.
+    pub failures: usize,
+}
+
+impl<A> Tracked<A> {
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            allocated: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    pub fn counters(&self) -> AllocCounters {
+        AllocCounters {
+            allocated: self.allocated.load(Ordering::Relaxed),
+            peak: self.peak.load(Ordering::Relaxed),
+            allocations: self.allocations.load(Ordering::Relaxed),
+            frees: self.frees.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Tracked<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if ptr.is_null() {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+            let allocated = self.allocated.fetch_add(layout.size(), Ordering::Relaxed);
+            self.peak
+                .fetch_max(allocated + layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        self.frees.fetch_add(1, Ordering::Relaxed);
+        self.allocated.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
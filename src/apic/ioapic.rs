@@ -0,0 +1,117 @@
+use acpi::platform::interrupt::IoApic as IoApicInfo;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+use crate::memory::mmio::{ioremap, CachePolicy, MmioRegion};
+
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+const MASKED: u64 = 1 << 16;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+const ACTIVE_LOW: u64 = 1 << 13;
+
+static IO_APICS: OnceCell<Mutex<Vec<IoApic>>> = OnceCell::uninit();
+
+/// An IO APIC, delivering the global system interrupts from `gsi_base` on.
+struct IoApic {
+    mmio: MmioRegion,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn new(phys: PhysAddr, gsi_base: u32) -> Self {
+        let mmio = ioremap(phys, 0x20, CachePolicy::Uncacheable).expect("failed to map IO APIC");
+        let mut io_apic = IoApic {
+            mmio,
+            gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = ((io_apic.read(IOAPICVER) >> 16) & 0xFF) + 1;
+        for index in 0..io_apic.entries {
+            io_apic.write_entry(index, MASKED);
+        }
+        io_apic
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    fn read(&self, register: u32) -> u32 {
+        self.mmio.write(IOREGSEL, register);
+        self.mmio.read(IOWIN)
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        self.mmio.write(IOREGSEL, register);
+        self.mmio.write(IOWIN, value);
+    }
+
+    fn read_entry(&self, index: u32) -> u64 {
+        let register = IOREDTBL + 2 * index;
+        self.read(register) as u64 | (self.read(register + 1) as u64) << 32
+    }
+
+    fn write_entry(&self, index: u32, entry: u64) {
+        let register = IOREDTBL + 2 * index;
+        // keep the entry masked while its halves disagree
+        self.write(register, MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+/// Maps every IO APIC listed in the MADT, with all of their inputs masked.
+pub fn init(io_apics: &[IoApicInfo]) {
+    let io_apics = io_apics
+        .iter()
+        .map(|info| {
+            IoApic::new(
+                PhysAddr::new(info.address as u64),
+                info.global_system_interrupt_base,
+            )
+        })
+        .collect();
+    IO_APICS.init_once(|| Mutex::new(io_apics));
+}
+
+/// Delivers global system interrupt `gsi` as `vector` to the local APIC with
+/// ID `destination` and unmasks it.
+pub fn route(gsi: u32, vector: u8, destination: u8, active_low: bool, level_triggered: bool) {
+    let mut entry = vector as u64 | (destination as u64) << 56;
+    if active_low {
+        entry |= ACTIVE_LOW;
+    }
+    if level_triggered {
+        entry |= LEVEL_TRIGGERED;
+    }
+    with_io_apic(gsi, |io_apic, index| io_apic.write_entry(index, entry));
+}
+
+pub fn set_masked(gsi: u32, masked: bool) {
+    with_io_apic(gsi, |io_apic, index| {
+        let entry = io_apic.read_entry(index);
+        let entry = if masked {
+            entry | MASKED
+        } else {
+            entry & !MASKED
+        };
+        io_apic.write_entry(index, entry);
+    });
+}
+
+fn with_io_apic(gsi: u32, f: impl FnOnce(&IoApic, u32)) {
+    let io_apics = IO_APICS.get().expect("IO APICs not initialized").lock();
+    let io_apic = io_apics
+        .iter()
+        .find(|io_apic| io_apic.handles(gsi))
+        .unwrap_or_else(|| panic!("no IO APIC handles GSI {gsi}"));
+    f(io_apic, gsi - io_apic.gsi_base);
+}
@@ -0,0 +1,129 @@
+use core::hint::spin_loop;
+
+use conquer_once::spin::OnceCell;
+use x86_64::{
+    instructions::interrupts::without_interrupts, registers::model_specific::Msr, PhysAddr,
+};
+
+use crate::memory::mmio::{ioremap, CachePolicy, MmioRegion};
+
+const ID: usize = 0x20;
+const TPR: usize = 0x80; // Task priority
+const EOI: usize = 0xB0;
+const SVR: usize = 0xF0; // Spurious interrupt vector
+const ICR_LOW: usize = 0x300; // Interrupt command
+const ICR_HIGH: usize = 0x310;
+const LVT_TIMER: usize = 0x320;
+const LVT_LINT0: usize = 0x350;
+const LVT_ERROR: usize = 0x370;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_DIVIDE: usize = 0x3E0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+const ICR_NMI: u32 = 0b100 << 8;
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_PENDING: u32 = 1 << 12;
+const ICR_ASSERT: u32 = 1 << 14;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// Vector the local APIC raises for spurious interrupts, which need no EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+/// Vector of the local APIC timer. Application processors use it to wake up
+/// and look for work.
+pub const TIMER_VECTOR: u8 = 0xFE;
+/// Vector of the IPI asking a CPU to flush its TLB, see `smp::tlb`.
+pub const TLB_FLUSH_VECTOR: u8 = 0xFD;
+
+static LAPIC: OnceCell<MmioRegion> = OnceCell::uninit();
+
+/// Maps the local APIC at `phys` and enables it on the current CPU.
+pub fn init(phys: PhysAddr) {
+    LAPIC.init_once(|| {
+        ioremap(phys, 0x400, CachePolicy::Uncacheable).expect("failed to map the local APIC")
+    });
+    enable();
+}
+
+/// Enables the current CPU's local APIC with its own interrupt sources
+/// masked, so that it only delivers what the IO APIC sends it.
+pub fn enable() {
+    unsafe {
+        let mut base = Msr::new(IA32_APIC_BASE);
+        base.write(base.read() | APIC_BASE_ENABLE);
+    }
+
+    write(LVT_TIMER, LVT_MASKED);
+    // LINT0 carries the 8259 in virtual wire mode
+    write(LVT_LINT0, LVT_MASKED);
+    write(LVT_ERROR, LVT_MASKED);
+    write(TPR, 0);
+    write(SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+/// The current CPU's local APIC ID.
+pub fn id() -> u8 {
+    (read(ID) >> 24) as u8
+}
+
+/// Signals the end of the interrupt currently being serviced.
+pub fn eoi() {
+    write(EOI, 0);
+}
+
+/// Raises `TIMER_VECTOR` on the current CPU every `count` ticks of the bus
+/// clock divided by 16.
+pub fn start_timer(count: u32) {
+    write(TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(LVT_TIMER, TIMER_PERIODIC | TIMER_VECTOR as u32);
+    write(TIMER_INITIAL_COUNT, count);
+}
+
+/// Sends an INIT IPI to the local APIC with ID `destination`, resetting its
+/// CPU into the wait-for-SIPI state.
+pub fn send_init(destination: u8) {
+    send_ipi(destination, ICR_INIT | ICR_ASSERT);
+}
+
+/// Sends a startup IPI to the local APIC with ID `destination`, starting its
+/// CPU in real mode at physical address `page * 4096`.
+pub fn send_startup(destination: u8, page: u8) {
+    send_ipi(destination, ICR_STARTUP | ICR_ASSERT | page as u32);
+}
+
+/// Sends a non-maskable interrupt to the CPU whose local APIC has ID
+/// `destination`.
+pub fn send_nmi(destination: u8) {
+    send_ipi(destination, ICR_NMI | ICR_ASSERT);
+}
+
+/// Raises `vector` on the CPU whose local APIC has ID `destination`.
+pub fn send_fixed(destination: u8, vector: u8) {
+    send_ipi(destination, ICR_ASSERT | vector as u32);
+}
+
+fn send_ipi(destination: u8, command: u32) {
+    // an IPI sent by an interrupt handler in between would change the
+    // destination
+    without_interrupts(|| {
+        write(ICR_HIGH, (destination as u32) << 24);
+        write(ICR_LOW, command);
+        while read(ICR_LOW) & ICR_PENDING != 0 {
+            spin_loop();
+        }
+    });
+}
+
+fn read(register: usize) -> u32 {
+    LAPIC.get().unwrap().read(register)
+}
+
+fn write(register: usize, value: u32) {
+    LAPIC.get().unwrap().write(register, value)
+}
@@ -0,0 +1,101 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use acpi::{
+    platform::interrupt::{Apic, Polarity, TriggerMode},
+    InterruptModel,
+};
+use conquer_once::spin::OnceCell;
+use x86_64::PhysAddr;
+
+pub mod ioapic;
+pub mod lapic;
+
+/// Writes to this window reach the local APICs rather than memory.
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static ISA_ROUTES: OnceCell<[IsaRoute; 16]> = OnceCell::uninit();
+
+/// How an ISA IRQ is wired to the IO APICs.
+#[derive(Debug, Clone, Copy)]
+pub struct IsaRoute {
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// Sets up the local and IO APICs described by the MADT.
+///
+/// Every IO APIC input starts out masked. Returns `false` if the firmware
+/// doesn't report an APIC, in which case the 8259 has to stay in charge.
+pub fn init() -> bool {
+    let Some(InterruptModel::Apic(apic)) = crate::acpi::platform_info().map(|i| i.interrupt_model)
+    else {
+        return false;
+    };
+
+    lapic::init(PhysAddr::new(apic.local_apic_address));
+    ioapic::init(&apic.io_apics);
+    ISA_ROUTES.init_once(|| isa_routes(&apic));
+    ENABLED.store(true, Ordering::Relaxed);
+    true
+}
+
+/// Whether interrupts are delivered through the APICs rather than the 8259.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Returns where ISA IRQ `irq` ends up, following the MADT's interrupt source
+/// overrides.
+pub fn isa_route(irq: u8) -> IsaRoute {
+    ISA_ROUTES.get().expect("APIC not initialized")[irq as usize]
+}
+
+/// Delivers ISA IRQ `irq` as `vector` to the current CPU.
+pub fn route_isa_irq(irq: u8, vector: u8) {
+    let route = isa_route(irq);
+    ioapic::route(
+        route.gsi,
+        vector,
+        lapic::id(),
+        route.active_low,
+        route.level_triggered,
+    );
+}
+
+/// What a PCI device writes, and where, to raise an interrupt through MSI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+}
+
+/// The message that delivers `vector` to the current CPU, edge triggered
+/// and with fixed delivery.
+pub fn msi_message(vector: u8) -> MsiMessage {
+    MsiMessage {
+        address: MSI_ADDRESS_BASE | (lapic::id() as u64) << 12,
+        data: vector as u32,
+    }
+}
+
+/// ISA interrupts are edge triggered and active high unless the MADT
+/// overrides them, as it usually does for the PIT on GSI 2.
+fn isa_routes(apic: &Apic) -> [IsaRoute; 16] {
+    let mut routes: [IsaRoute; 16] = core::array::from_fn(|irq| IsaRoute {
+        gsi: irq as u32,
+        active_low: false,
+        level_triggered: false,
+    });
+    for source in &apic.interrupt_source_overrides {
+        if let Some(route) = routes.get_mut(source.isa_source as usize) {
+            *route = IsaRoute {
+                gsi: source.global_system_interrupt,
+                active_low: matches!(source.polarity, Polarity::ActiveLow),
+                level_triggered: matches!(source.trigger_mode, TriggerMode::Level),
+            };
+        }
+    }
+    routes
+}
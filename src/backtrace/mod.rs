@@ -0,0 +1,116 @@
+use core::{arch::asm, fmt};
+
+use x86_64::VirtAddr;
+
+use crate::{memory::address_space::is_mapped, println, serial_println};
+
+pub mod symbols;
+
+use symbols::Demangled;
+
+/// Frames shown before a backtrace is cut off, in case the chain of frame
+/// pointers loops.
+const MAX_FRAMES: usize = 32;
+
+/// Walks the chain of saved frame pointers starting at `rbp` and yields the
+/// return address stored in each frame, innermost first.
+///
+/// The kernel is built with frame pointers, so every frame starts with the
+/// caller's `rbp` followed by the return address. The walk stops at the
+/// first pointer that isn't mapped or doesn't lead up the stack.
+pub fn frames(mut rbp: u64) -> impl Iterator<Item = u64> {
+    core::iter::from_fn(move || {
+        let readable = |addr: u64| VirtAddr::try_new(addr).is_ok_and(is_mapped);
+        if rbp == 0 || rbp % 8 != 0 || !readable(rbp) || !readable(rbp + 8) {
+            return None;
+        }
+        let (next, return_addr) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if return_addr == 0 {
+            return None;
+        }
+        // callers' frames are always further up the stack
+        rbp = if next > rbp { next } else { 0 };
+        Some(return_addr)
+    })
+    .take(MAX_FRAMES)
+}
+
+/// The frame pointer of the calling function.
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    rbp
+}
+
+/// Prints the backtrace of the caller to VGA and serial.
+#[inline(never)]
+pub fn print() {
+    let mut frames = frames(frame_pointer());
+    // the first return address is our caller, which is where the trace starts
+    if let Some(caller) = frames.next() {
+        print_frames(caller, frames);
+    }
+}
+
+/// Prints the backtrace of code interrupted at `rip` with frame pointer
+/// `rbp` to VGA and serial.
+pub fn print_from(rip: u64, rbp: u64) {
+    print_frames(rip, frames(rbp));
+}
+
+fn print_frames(first: u64, rest: impl Iterator<Item = u64>) {
+    println!("Backtrace:");
+    serial_println!("Backtrace:");
+    let first = Frame {
+        addr: first,
+        is_return_addr: false,
+    };
+    let rest = rest.map(|addr| Frame {
+        addr,
+        is_return_addr: true,
+    });
+    for (index, frame) in core::iter::once(first).chain(rest).enumerate() {
+        println!("  #{:<2} {}", index, frame);
+        serial_println!("  #{:<2} {}", index, frame);
+    }
+}
+
+struct Frame {
+    addr: u64,
+    is_return_addr: bool,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#018x}", self.addr)?;
+        // a return address may already be past the end of a call that never
+        // returns, so look up the call itself
+        let lookup_addr = if self.is_return_addr {
+            self.addr - 1
+        } else {
+            self.addr
+        };
+        if let Some((name, offset)) = symbols::lookup(lookup_addr) {
+            let offset = offset + (self.addr - lookup_addr);
+            write!(f, " {}+{:#x}", Demangled(name), offset)?;
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_frames() {
+    #[inline(never)]
+    fn depth() -> usize {
+        frames(frame_pointer()).count()
+    }
+
+    #[inline(never)]
+    fn nested() -> usize {
+        depth()
+    }
+
+    assert!(depth() > 0);
+    assert_eq!(nested(), depth() + 1);
+}
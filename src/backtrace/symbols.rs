@@ -0,0 +1,196 @@
+use core::{
+    arch::global_asm,
+    fmt::{self, Write},
+    mem, slice,
+};
+
+use crate::println;
+
+/// Room for the symbol table in the kernel image. `embed-symbols` fails if
+/// the table doesn't fit, which is the cue to raise this.
+const TABLE_SIZE: usize = 1 << 20;
+const HEADER_SIZE: usize = 16;
+const MAGIC: [u8; 8] = *b"KSYMTAB1";
+
+// The table is empty when the kernel is linked. The `embed-symbols` runner
+// fills it in from the kernel's ELF symbol table before the kernel boots,
+// and since it's part of the loaded image, it doesn't matter what the
+// bootloader does with the rest of the file.
+global_asm!(
+    ".pushsection .kernel_symbols, \"a\", @progbits",
+    ".balign 8",
+    ".global KERNEL_SYMBOLS",
+    "KERNEL_SYMBOLS:",
+    ".ascii \"KSYMTAB1\"",
+    ".quad 0",
+    ".space {space}",
+    ".popsection",
+    space = const TABLE_SIZE - HEADER_SIZE,
+);
+
+extern "C" {
+    static KERNEL_SYMBOLS: SymbolTable;
+}
+
+/// The layout `embed-symbols` writes.
+#[repr(C)]
+struct SymbolTable {
+    magic: [u8; 8],
+    count: u64,
+    /// `count` symbols sorted by address, followed by their names.
+    data: [u8; TABLE_SIZE - HEADER_SIZE],
+}
+
+/// A function of the kernel image.
+#[repr(C)]
+struct Symbol {
+    start: u64,
+    size: u32,
+    /// Offset of the NUL-terminated name in `SymbolTable::data`.
+    name: u32,
+}
+
+/// Reports whether backtraces will have function names.
+pub fn init() {
+    if symbols().is_empty() {
+        println!("backtrace: no symbol table embedded, run the kernel through embed-symbols");
+    }
+}
+
+fn symbols() -> &'static [Symbol] {
+    let table = unsafe { &KERNEL_SYMBOLS };
+    let count = table.count as usize;
+    if table.magic != MAGIC || count > table.data.len() / mem::size_of::<Symbol>() {
+        return &[];
+    }
+    unsafe { slice::from_raw_parts(table.data.as_ptr().cast(), count) }
+}
+
+fn name(symbol: &Symbol) -> Option<&'static str> {
+    let data = unsafe { &KERNEL_SYMBOLS.data };
+    let name = data.get(symbol.name as usize..)?;
+    let len = name.iter().position(|&byte| byte == 0)?;
+    core::str::from_utf8(&name[..len]).ok()
+}
+
+/// Finds the function containing `addr` and returns its mangled name and
+/// the offset of `addr` into it.
+pub fn lookup(addr: u64) -> Option<(&'static str, u64)> {
+    let symbols = symbols();
+    let index = symbols.partition_point(|symbol| symbol.start <= addr);
+    let symbol = symbols.get(index.checked_sub(1)?)?;
+    let offset = addr - symbol.start;
+    if offset >= (symbol.size as u64).max(1) {
+        return None;
+    }
+    Some((name(symbol)?, offset))
+}
+
+/// Displays a symbol name without its legacy Rust mangling.
+///
+/// Names that aren't mangled are shown as they are.
+pub struct Demangled<'a>(pub &'a str);
+
+impl fmt::Display for Demangled<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(mut rest) = self.0.strip_prefix("_ZN") else {
+            return f.write_str(self.0);
+        };
+
+        let mut first = true;
+        loop {
+            let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+            let Ok(len) = rest[..digits].parse::<usize>() else {
+                break;
+            };
+            let Some(ident) = rest.get(digits..digits + len) else {
+                break;
+            };
+            rest = &rest[digits + len..];
+            if rest == "E" && is_hash(ident) {
+                break;
+            }
+
+            if !first {
+                f.write_str("::")?;
+            }
+            first = false;
+            write_ident(f, ident)?;
+        }
+        Ok(())
+    }
+}
+
+fn is_hash(ident: &str) -> bool {
+    ident.len() == 17
+        && ident.starts_with('h')
+        && ident[1..].bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// Writes a path segment, undoing the escapes legacy mangling uses for
+/// characters that can't appear in symbols.
+fn write_ident(f: &mut fmt::Formatter<'_>, ident: &str) -> fmt::Result {
+    // identifiers starting with an escape get an underscore in front
+    let mut rest = if ident.starts_with("_$") {
+        &ident[1..]
+    } else {
+        ident
+    };
+    while let Some(c) = rest.chars().next() {
+        if let Some(after) = rest.strip_prefix("..") {
+            f.write_str("::")?;
+            rest = after;
+            continue;
+        }
+        if c == '$' {
+            if let Some((escape, after)) = rest[1..].split_once('$') {
+                if let Some(unescaped) = unescape(escape) {
+                    f.write_char(unescaped)?;
+                    rest = after;
+                    continue;
+                }
+            }
+        }
+        f.write_char(c)?;
+        rest = &rest[c.len_utf8()..];
+    }
+    Ok(())
+}
+
+fn unescape(escape: &str) -> Option<char> {
+    Some(match escape {
+        "SP" => '@',
+        "BP" => '*',
+        "RF" => '&',
+        "LT" => '<',
+        "GT" => '>',
+        "LP" => '(',
+        "RP" => ')',
+        "C" => ',',
+        _ => char::from_u32(u32::from_str_radix(escape.strip_prefix('u')?, 16).ok()?)?,
+    })
+}
+
+#[test_case]
+fn test_lookup() {
+    let addr = test_lookup as usize as u64;
+    let (name, offset) = lookup(addr + 1).expect("no symbol table embedded");
+    assert_eq!(offset, 1);
+    assert!(name.contains("test_lookup"));
+}
+
+#[test_case]
+fn test_demangle() {
+    use alloc::format;
+
+    let demangle = |name| format!("{}", Demangled(name));
+    assert_eq!(
+        demangle("_ZN7blog_os9backtrace5print17h0123456789abcdefE"),
+        "blog_os::backtrace::print"
+    );
+    assert_eq!(
+        demangle("_ZN58_$LT$blog_os..task..TaskId$u20$as$u20$core..fmt..Debug$GT$3fmt17h0123456789abcdefE"),
+        "<blog_os::task::TaskId as core::fmt::Debug>::fmt"
+    );
+    assert_eq!(demangle("exception_handler"), "exception_handler");
+}
@@ -0,0 +1,195 @@
+use core::{
+    arch::asm,
+    fmt,
+    hint::spin_loop,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    registers::{
+        control::{Cr0, Cr2, Cr3, Cr4},
+        rflags,
+    },
+};
+
+use crate::{
+    apic::lapic, backtrace, drivers::fw_cfg::FwCfg, exceptions::ExceptionFrame, exit_qemu,
+    hlt_loop, println, serial::SERIAL1, serial_println, smp, smp::percpu, vga_buffer::WRITER,
+    QemuExitCode,
+};
+
+/// Boot option that makes a crash exit QEMU with `QemuExitCode::Failed`
+/// instead of halting, given as
+/// `-fw_cfg name=opt/blog_os/exit_on_crash,string=1`.
+const EXIT_ON_CRASH_OPTION: &str = "opt/blog_os/exit_on_crash";
+
+/// Polls of a held output lock before it is taken away from its holder, and
+/// of the other CPUs before the report goes on without them stopping.
+const LOCK_SPINS: usize = 1_000_000;
+
+const NO_CPU: usize = usize::MAX;
+
+static EXIT_ON_CRASH: AtomicBool = AtomicBool::new(false);
+/// Index of the CPU reporting a crash, `NO_CPU` until there is one.
+static CRASHING_CPU: AtomicUsize = AtomicUsize::new(NO_CPU);
+/// One bit for each CPU that stopped for the report, by index.
+static PARKED: AtomicU64 = AtomicU64::new(0);
+
+/// Reads the boot options deciding what happens after a crash.
+pub fn init() {
+    let mut value = [0; 1];
+    let len =
+        FwCfg::new().and_then(|mut fw_cfg| fw_cfg.read_file(EXIT_ON_CRASH_OPTION, &mut value));
+    EXIT_ON_CRASH.store(len == Some(1) && value[0] == b'1', Ordering::Relaxed);
+}
+
+/// Reports a panic and stops the kernel.
+pub fn panic(info: &PanicInfo) -> ! {
+    crash(info, None)
+}
+
+/// Stops the other CPUs, writes `message`, the CPU state and a backtrace to
+/// VGA and serial, then halts the CPU or exits QEMU, as the boot options say.
+///
+/// The state is taken from `frame` if the crash was caused by an exception,
+/// otherwise from the caller.
+#[inline(never)]
+pub fn crash(message: &dyn fmt::Display, frame: Option<&ExceptionFrame>) -> ! {
+    interrupts::disable();
+    let this = this_cpu();
+    match CRASHING_CPU.compare_exchange(NO_CPU, this, Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => {}
+        // crashing while reporting a crash, the first report is all we get
+        Err(cpu) if cpu == this => stop(),
+        // another CPU got to report first
+        Err(_) => park(),
+    }
+
+    // the other CPUs could still be using the locks, so they stop first
+    stop_other_cpus();
+    // whoever holds the locks now was interrupted by the crash or stopped,
+    // and the report matters more than their output
+    break_lock(&*WRITER);
+    break_lock(&*SERIAL1);
+
+    println!("KERNEL CRASH: {}", message);
+    serial_println!("KERNEL CRASH: {}", message);
+    match frame {
+        Some(frame) => {
+            println!("{}", frame);
+            serial_println!("{}", frame);
+            backtrace::print_from(frame.rip, frame.rbp);
+        }
+        None => {
+            let state = CpuState::capture();
+            println!("{}", state);
+            serial_println!("{}", state);
+            backtrace::print();
+        }
+    }
+    stop()
+}
+
+/// Whether a CPU is reporting a crash.
+pub fn is_crashing() -> bool {
+    CRASHING_CPU.load(Ordering::SeqCst) != NO_CPU
+}
+
+/// Halts the calling CPU for good, while another one reports a crash.
+///
+/// The crashing CPU sends the others an NMI, whose handler ends up here.
+pub fn park() -> ! {
+    interrupts::disable();
+    PARKED.fetch_or(cpu_bit(this_cpu()), Ordering::SeqCst);
+    hlt_loop()
+}
+
+/// Sends every other online CPU an NMI to park it, and gives them a while to
+/// get there.
+fn stop_other_cpus() {
+    let this = this_cpu();
+    let mut others = 0;
+    for (index, cpu) in smp::cpus().iter().enumerate() {
+        if index != this && cpu.is_online() {
+            lapic::send_nmi(cpu.apic_id);
+            others |= cpu_bit(index);
+        }
+    }
+    for _ in 0..LOCK_SPINS {
+        if PARKED.load(Ordering::SeqCst) & others == others {
+            return;
+        }
+        spin_loop();
+    }
+}
+
+fn this_cpu() -> usize {
+    percpu::try_current().map_or(0, |cpu| cpu.id())
+}
+
+/// The bit of CPU `index` in `PARKED`. CPUs past the 64th aren't waited for.
+fn cpu_bit(index: usize) -> u64 {
+    1u64.checked_shl(index as u32).unwrap_or(0)
+}
+
+fn stop() -> ! {
+    if EXIT_ON_CRASH.load(Ordering::Relaxed) {
+        exit_qemu(QemuExitCode::Failed);
+    }
+    // with interrupts disabled, this never wakes up again
+    hlt_loop()
+}
+
+fn break_lock<T>(lock: &Mutex<T>) {
+    for _ in 0..LOCK_SPINS {
+        if !lock.is_locked() {
+            return;
+        }
+        spin_loop();
+    }
+    unsafe { lock.force_unlock() };
+}
+
+/// The registers worth showing when there's no exception frame.
+struct CpuState {
+    rsp: u64,
+    rbp: u64,
+    rflags: u64,
+}
+
+impl CpuState {
+    #[inline(always)]
+    fn capture() -> Self {
+        let (rsp, rbp): (u64, u64);
+        unsafe {
+            asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+        }
+        CpuState {
+            rsp,
+            rbp,
+            rflags: rflags::read_raw(),
+        }
+    }
+}
+
+impl fmt::Display for CpuState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "RSP={:016x} RBP={:016x} RFLAGS={:016x}",
+            self.rsp, self.rbp, self.rflags
+        )?;
+        writeln!(
+            f,
+            "CR0={:016x} CR2={:016x} CR3={:016x} CR4={:016x}",
+            Cr0::read_raw(),
+            Cr2::read().as_u64(),
+            Cr3::read().0.start_address().as_u64(),
+            Cr4::read_raw()
+        )?;
+        write!(f, "{}", percpu::CurrentContext)
+    }
+}
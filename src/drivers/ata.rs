@@ -0,0 +1,203 @@
+use x86_64::instructions::port::Port;
+
+use super::block::{BlockDevice, BlockError, SECTOR_SIZE};
+use crate::pci::{Bar, PciDevice};
+
+const STATUS_ERR: u8 = 1 << 0; // Error
+const STATUS_DRQ: u8 = 1 << 3; // Data request
+const STATUS_DF: u8 = 1 << 5; // Device fault
+const STATUS_BSY: u8 = 1 << 7; // Busy
+
+const CONTROL_NIEN: u8 = 1 << 1; // Disable interrupts
+
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_CACHE_FLUSH: u8 = 0xE7;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+/// Status polls before a command is given up on.
+const TIMEOUT: usize = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Primary,
+    Secondary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drive {
+    Master,
+    Slave,
+}
+
+/// An ATA disk on an IDE controller, driven with polled PIO and 28-bit LBA.
+///
+/// The drive's interrupts are disabled, so it doesn't need an IRQ handler.
+pub struct AtaDrive {
+    slave: bool,
+    sectors: u64,
+    data: Port<u16>,
+    error: Port<u8>,
+    sector_count: Port<u8>,
+    lba: [Port<u8>; 3],
+    drive_head: Port<u8>,
+    command: Port<u8>,
+    control: Port<u8>,
+}
+
+impl AtaDrive {
+    /// Finds `drive` on `channel` of the IDE controller `ide`.
+    ///
+    /// Returns `None` if there's no ATA disk attached there.
+    pub fn new(ide: &PciDevice, channel: Channel, drive: Drive) -> Option<Self> {
+        let (io_base, control_base) = ports(ide, channel);
+        let mut ata = AtaDrive {
+            slave: drive == Drive::Slave,
+            sectors: 0,
+            data: Port::new(io_base),
+            error: Port::new(io_base + 1),
+            sector_count: Port::new(io_base + 2),
+            lba: [
+                Port::new(io_base + 3),
+                Port::new(io_base + 4),
+                Port::new(io_base + 5),
+            ],
+            drive_head: Port::new(io_base + 6),
+            command: Port::new(io_base + 7),
+            control: Port::new(control_base),
+        };
+        ata.sectors = ata.identify()?;
+        Some(ata)
+    }
+
+    /// Returns the number of addressable sectors.
+    fn identify(&mut self) -> Option<u64> {
+        unsafe {
+            self.control.write(CONTROL_NIEN);
+            self.select(0);
+            self.sector_count.write(0);
+            self.command.write(CMD_IDENTIFY);
+            if self.command.read() == 0 {
+                return None;
+            }
+            // ATAPI and SATA devices put their signature in the LBA registers
+            self.wait_idle().ok()?;
+            if self.lba[1].read() != 0 || self.lba[2].read() != 0 {
+                return None;
+            }
+            self.wait_data().ok()?;
+
+            let mut identity = [0u16; 256];
+            for word in identity.iter_mut() {
+                *word = self.data.read();
+            }
+            Some(identity[60] as u64 | (identity[61] as u64) << 16)
+        }
+    }
+
+    /// Selects the drive and loads `lba` into the address registers.
+    unsafe fn select(&mut self, lba: u64) {
+        let slave = if self.slave { 1 << 4 } else { 0 };
+        self.drive_head
+            .write(0xE0 | slave | ((lba >> 24) & 0x0F) as u8);
+        // give the drive 400ns to switch
+        for _ in 0..4 {
+            self.control.read();
+        }
+        for (i, port) in self.lba.iter_mut().enumerate() {
+            port.write((lba >> (8 * i)) as u8);
+        }
+    }
+
+    /// Starts `command` on `count` sectors, which must be at most 256.
+    unsafe fn start(&mut self, command: u8, lba: u64, count: usize) -> Result<(), BlockError> {
+        if lba + count as u64 > self.sectors {
+            return Err(BlockError::OutOfRange);
+        }
+        self.wait_idle()?;
+        self.select(lba);
+        // a count of zero means 256 sectors
+        self.sector_count.write(count as u8);
+        self.command.write(command);
+        Ok(())
+    }
+
+    fn wait_idle(&mut self) -> Result<u8, BlockError> {
+        for _ in 0..TIMEOUT {
+            let status = unsafe { self.control.read() };
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+        }
+        Err(BlockError::Timeout)
+    }
+
+    fn wait_data(&mut self) -> Result<(), BlockError> {
+        for _ in 0..TIMEOUT {
+            let status = self.wait_idle()?;
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                return Err(BlockError::Device(unsafe { self.error.read() }));
+            }
+            if status & STATUS_DRQ != 0 {
+                return Ok(());
+            }
+        }
+        Err(BlockError::Timeout)
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        for (i, chunk) in buf.chunks_mut(256 * SECTOR_SIZE).enumerate() {
+            let lba = lba + (i * 256) as u64;
+            unsafe { self.start(CMD_READ_SECTORS, lba, chunk.len() / SECTOR_SIZE)? };
+            for sector in chunk.chunks_exact_mut(SECTOR_SIZE) {
+                self.wait_data()?;
+                for bytes in sector.chunks_exact_mut(2) {
+                    let word = unsafe { self.data.read() };
+                    bytes.copy_from_slice(&word.to_le_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        for (i, chunk) in buf.chunks(256 * SECTOR_SIZE).enumerate() {
+            let lba = lba + (i * 256) as u64;
+            unsafe { self.start(CMD_WRITE_SECTORS, lba, chunk.len() / SECTOR_SIZE)? };
+            for sector in chunk.chunks_exact(SECTOR_SIZE) {
+                self.wait_data()?;
+                for bytes in sector.chunks_exact(2) {
+                    unsafe { self.data.write(u16::from_le_bytes([bytes[0], bytes[1]])) };
+                }
+            }
+        }
+
+        self.wait_idle()?;
+        unsafe { self.command.write(CMD_CACHE_FLUSH) };
+        self.wait_idle().map(|_| ())
+    }
+}
+
+/// Returns the command block and control ports of `channel`.
+///
+/// Channels in native mode use the controller's BARs, the others sit at the
+/// legacy ISA ports.
+fn ports(ide: &PciDevice, channel: Channel) -> (u16, u16) {
+    let (native_bit, bar, legacy) = match channel {
+        Channel::Primary => (0, 0, (0x1F0, 0x3F6)),
+        Channel::Secondary => (2, 2, (0x170, 0x376)),
+    };
+    if ide.prog & (1 << native_bit) == 0 {
+        return legacy;
+    }
+    match (ide.bar(bar), ide.bar(bar + 1)) {
+        (Some(Bar::Io { port, .. }), Some(Bar::Io { port: control, .. })) => (port, control + 2),
+        _ => legacy,
+    }
+}
@@ -0,0 +1,23 @@
+pub const SECTOR_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request reaches past the last sector of the device.
+    OutOfRange,
+    /// The device didn't become ready in time.
+    Timeout,
+    /// The device reported an error, with its error register.
+    Device(u8),
+}
+
+/// A device that stores data in fixed size sectors.
+///
+/// Buffers passed to `read_sectors` and `write_sectors` must be a multiple of
+/// `SECTOR_SIZE` long and cover that many sectors starting at `lba`.
+pub trait BlockDevice {
+    fn sector_count(&self) -> u64;
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+}
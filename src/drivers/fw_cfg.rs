@@ -0,0 +1,67 @@
+use x86_64::instructions::port::Port;
+
+const SELECTOR_PORT: u16 = 0x510;
+const DATA_PORT: u16 = 0x511;
+
+const KEY_SIGNATURE: u16 = 0x00;
+const KEY_FILE_DIR: u16 = 0x19;
+
+const FILE_NAME_SIZE: usize = 56;
+
+/// QEMU's firmware configuration device, which hands files given with
+/// `-fw_cfg name=<name>,string=<value>` to the guest.
+///
+/// Reads aren't synchronized, so this is only meant for boot time.
+pub struct FwCfg {
+    selector: Port<u16>,
+    data: Port<u8>,
+}
+
+impl FwCfg {
+    /// Returns `None` if the machine has no fw_cfg device.
+    pub fn new() -> Option<Self> {
+        let mut fw_cfg = FwCfg {
+            selector: Port::new(SELECTOR_PORT),
+            data: Port::new(DATA_PORT),
+        };
+        fw_cfg.select(KEY_SIGNATURE);
+        (fw_cfg.read::<4>() == *b"QEMU").then_some(fw_cfg)
+    }
+
+    /// Reads the file `name` into `buf` and returns how many bytes were
+    /// read, or `None` if there's no such file.
+    pub fn read_file(&mut self, name: &str, buf: &mut [u8]) -> Option<usize> {
+        self.select(KEY_FILE_DIR);
+        let count = u32::from_be_bytes(self.read());
+        for _ in 0..count {
+            let size = u32::from_be_bytes(self.read()) as usize;
+            let key = u16::from_be_bytes(self.read());
+            let _reserved: [u8; 2] = self.read();
+            let file_name: [u8; FILE_NAME_SIZE] = self.read();
+
+            let len = file_name.iter().position(|&byte| byte == 0);
+            if file_name[..len.unwrap_or(FILE_NAME_SIZE)] == *name.as_bytes() {
+                self.select(key);
+                let len = size.min(buf.len());
+                for byte in &mut buf[..len] {
+                    *byte = unsafe { self.data.read() };
+                }
+                return Some(len);
+            }
+        }
+        None
+    }
+
+    fn select(&mut self, key: u16) {
+        unsafe { self.selector.write(key) };
+    }
+
+    /// Reads the next `N` bytes of the selected item.
+    fn read<const N: usize>(&mut self) -> [u8; N] {
+        let mut bytes = [0; N];
+        for byte in &mut bytes {
+            *byte = unsafe { self.data.read() };
+        }
+        bytes
+    }
+}
@@ -1 +1,4 @@
+pub mod ata;
+pub mod block;
+pub mod fw_cfg;
 pub mod net;
@@ -6,12 +6,12 @@ use smoltcp::{
     phy::{DeviceCapabilities, Medium},
     wire::{EthernetAddress, HardwareAddress},
 };
-use x86_64::{instructions::port::Port, structures::idt::InterruptStackFrame};
+use x86_64::instructions::port::Port;
 
 use crate::{
-    allocator::PhysBuf,
-    interrupts::{IDT, PICS},
-    networking::EthernetDevice,
+    irq,
+    memory::dma::{DmaBuffer, DmaConstraints},
+    networking::{copy_packet, EthernetDevice},
     pci, println,
     task::network::{notify_rx, notify_tx},
 };
@@ -57,9 +57,9 @@ pub struct Rtl8139 {
     tx_buffer_ports: [Port<u32>; 4],
     tx_status_ports: [Port<u32>; 4],
 
-    tx_buffers: [PhysBuf; 4],
+    tx_buffers: [DmaBuffer; 4],
     current_tx_buffer: AtomicUsize,
-    rx_buffer: PhysBuf,
+    rx_buffer: DmaBuffer,
     rx_offset: usize,
 }
 
@@ -93,14 +93,14 @@ impl Rtl8139 {
             ],
 
             tx_buffers: [
-                PhysBuf::new(4096),
-                PhysBuf::new(4096),
-                PhysBuf::new(4096),
-                PhysBuf::new(4096),
+                dma_buffer(4096),
+                dma_buffer(4096),
+                dma_buffer(4096),
+                dma_buffer(4096),
             ],
             current_tx_buffer: AtomicUsize::new(3),
 
-            rx_buffer: PhysBuf::new(9708),
+            rx_buffer: dma_buffer(RX_BUFFER_LEN),
             rx_offset: 0,
         };
 
@@ -120,20 +120,27 @@ impl Rtl8139 {
             }
 
             // Set physical addresses of our packet buffers
-            self.rx_buffer_port.write(self.rx_buffer.addr() as u32);
-            self.tx_buffer_ports[0].write(self.tx_buffers[0].addr() as u32);
-            self.tx_buffer_ports[1].write(self.tx_buffers[1].addr() as u32);
-            self.tx_buffer_ports[2].write(self.tx_buffers[2].addr() as u32);
-            self.tx_buffer_ports[3].write(self.tx_buffers[3].addr() as u32);
+            self.rx_buffer_port
+                .write(self.rx_buffer.phys_addr().as_u64() as u32);
+            for (port, buffer) in self.tx_buffer_ports.iter_mut().zip(&self.tx_buffers) {
+                port.write(buffer.phys_addr().as_u64() as u32);
+            }
 
             // Accept only Transmit OK and Receive OK interrupts
             self.imr.write(0x5);
             //self.isr.write(0x5);
 
-            let irq_num = pci::get_device(0x10EC, 0x8139).unwrap().read(0xF).byte(0);
-            println!("IRQ: {irq_num}");
-            let mut idt = IDT.get().unwrap().lock();
-            idt[32 + irq_num as usize].set_handler_fn(rtl8139_handler);
+            // the INTx line is shared with other devices, so prefer MSI
+            let device = pci::get_device(0x10EC, 0x8139).unwrap();
+            match device.register_msi(rtl8139_handler, &RTL_IO_BASE) {
+                Ok(vector) => println!("MSI vector: {vector}"),
+                Err(_) => {
+                    let irq_num = device.read(0xF).byte(0);
+                    println!("IRQ: {irq_num}");
+                    irq::register_irq(irq_num, rtl8139_handler, &RTL_IO_BASE)
+                        .expect("failed to register RTL8139 IRQ");
+                }
+            }
 
             // Accept all packets and write them past the end of the receive buffer
             self.rx_config.write(AB | AM | APM | AAP | WRAP);
@@ -144,10 +151,16 @@ impl Rtl8139 {
     }
 }
 
+/// The card only takes 32-bit buffer addresses.
+fn dma_buffer(len: usize) -> DmaBuffer {
+    DmaBuffer::with_constraints(len, DmaConstraints::ZONE_32BIT)
+        .expect("failed to allocate RTL8139 DMA buffer")
+}
+
 static RTL_IO_BASE: AtomicU16 = AtomicU16::new(0);
 
-extern "x86-interrupt" fn rtl8139_handler(_stack_frame: InterruptStackFrame) {
-    let mut isr: Port<u16> = Port::new(RTL_IO_BASE.load(Ordering::Relaxed) + 0x3E);
+fn rtl8139_handler(io_base: &AtomicU16) {
+    let mut isr: Port<u16> = Port::new(io_base.load(Ordering::Relaxed) + 0x3E);
 
     let status = unsafe {
         let s = isr.read();
@@ -162,10 +175,6 @@ extern "x86-interrupt" fn rtl8139_handler(_stack_frame: InterruptStackFrame) {
     if (status & 0x4) == 0x4 {
         //notify_tx();
     }
-
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(43);
-    }
 }
 
 impl EthernetDevice for Rtl8139 {
@@ -241,6 +250,6 @@ impl EthernetDevice for Rtl8139 {
         }
 
         //unsafe { self.ports.isr.write(0x1); }
-        Some(self.rx_buffer[(offset + 4)..(offset + n)].to_vec())
+        copy_packet(&self.rx_buffer[(offset + 4)..(offset + n)])
     }
 }
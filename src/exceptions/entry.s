@@ -0,0 +1,107 @@
+# Entry points of the CPU exceptions.
+#
+# Every entry pushes a zero in place of the error code if the CPU doesn't
+# push one, then the vector, so that all exceptions share one frame layout.
+# The common part saves the general purpose registers on top and passes the
+# resulting `ExceptionFrame` to `exception_handler`.
+
+.section .text
+
+.macro EXCEPTION vector, error_code
+exception_entry_\vector:
+.if \error_code == 0
+    push $0
+.endif
+    push $\vector
+    jmp exception_common
+.endm
+
+EXCEPTION 0, 0
+EXCEPTION 1, 0
+EXCEPTION 2, 0
+EXCEPTION 3, 0
+EXCEPTION 4, 0
+EXCEPTION 5, 0
+EXCEPTION 6, 0
+EXCEPTION 7, 0
+EXCEPTION 8, 1
+EXCEPTION 9, 0
+EXCEPTION 10, 1
+EXCEPTION 11, 1
+EXCEPTION 12, 1
+EXCEPTION 13, 1
+EXCEPTION 14, 1
+EXCEPTION 16, 0
+EXCEPTION 17, 1
+EXCEPTION 18, 0
+EXCEPTION 19, 0
+EXCEPTION 20, 0
+EXCEPTION 21, 1
+EXCEPTION 28, 0
+EXCEPTION 29, 1
+EXCEPTION 30, 1
+
+exception_common:
+    push %rax
+    push %rbx
+    push %rcx
+    push %rdx
+    push %rsi
+    push %rdi
+    push %rbp
+    push %r8
+    push %r9
+    push %r10
+    push %r11
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+
+    # coming from user mode, the kernel's GS base is still swapped out
+    testb $3, 144(%rsp)
+    jz 1f
+    swapgs
+1:
+    # the CPU aligned the stack before pushing its frame and we pushed an even
+    # number of words since, so the call below sees an aligned stack
+    mov %rsp, %rdi
+    cld
+    call exception_handler
+
+    testb $3, 144(%rsp)
+    jz 2f
+    swapgs
+2:
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %r11
+    pop %r10
+    pop %r9
+    pop %r8
+    pop %rbp
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %rcx
+    pop %rbx
+    pop %rax
+    # vector and error code
+    add $16, %rsp
+    iretq
+
+# Entry point of each vector below 32, zero for the reserved ones.
+.section .rodata
+.balign 8
+.global exception_entries
+exception_entries:
+    .quad exception_entry_0, exception_entry_1, exception_entry_2, exception_entry_3
+    .quad exception_entry_4, exception_entry_5, exception_entry_6, exception_entry_7
+    .quad exception_entry_8, exception_entry_9, exception_entry_10, exception_entry_11
+    .quad exception_entry_12, exception_entry_13, exception_entry_14, 0
+    .quad exception_entry_16, exception_entry_17, exception_entry_18, exception_entry_19
+    .quad exception_entry_20, exception_entry_21, 0, 0
+    .quad 0, 0, 0, 0
+    .quad exception_entry_28, exception_entry_29, exception_entry_30, 0
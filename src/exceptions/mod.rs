@@ -0,0 +1,287 @@
+use core::{arch::global_asm, fmt};
+
+use x86_64::{
+    registers::control::{Cr0, Cr2, Cr3, Cr4},
+    structures::idt::{Entry, HandlerFunc, InterruptDescriptorTable, PageFaultErrorCode},
+    VirtAddr,
+};
+
+use crate::{crash, gdt, interrupts, memory, println, serial_println, smp::percpu};
+
+global_asm!(include_str!("entry.s"), options(att_syntax));
+
+extern "C" {
+    static exception_entries: [u64; 32];
+}
+
+const DEBUG: u64 = 1;
+const NON_MASKABLE_INTERRUPT: u64 = 2;
+const BREAKPOINT: u64 = 3;
+const DOUBLE_FAULT: u64 = 8;
+const PAGE_FAULT: u64 = 14;
+
+const NAMES: [&str; 32] = [
+    "Divide Error",
+    "Debug",
+    "Non-Maskable Interrupt",
+    "Breakpoint",
+    "Overflow",
+    "Bound Range Exceeded",
+    "Invalid Opcode",
+    "Device Not Available",
+    "Double Fault",
+    "Coprocessor Segment Overrun",
+    "Invalid TSS",
+    "Segment Not Present",
+    "Stack-Segment Fault",
+    "General Protection Fault",
+    "Page Fault",
+    "Reserved",
+    "x87 Floating-Point Exception",
+    "Alignment Check",
+    "Machine Check",
+    "SIMD Floating-Point Exception",
+    "Virtualization Exception",
+    "Control Protection Exception",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Hypervisor Injection Exception",
+    "VMM Communication Exception",
+    "Security Exception",
+    "Reserved",
+];
+
+/// What the exception entry points save on the stack: the general purpose
+/// registers, the vector, the error code (zero for exceptions without one)
+/// and the frame pushed by the CPU.
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// The name of exception `vector`.
+pub fn name(vector: u64) -> &'static str {
+    NAMES.get(vector as usize).copied().unwrap_or("Unknown")
+}
+
+impl ExceptionFrame {
+    pub fn name(&self) -> &'static str {
+        name(self.vector)
+    }
+
+    fn has_error_code(&self) -> bool {
+        matches!(self.vector, 8 | 10..=14 | 17 | 21 | 29 | 30)
+    }
+}
+
+impl fmt::Display for ExceptionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "EXCEPTION: {} (vector {})", self.name(), self.vector)?;
+        if self.has_error_code() {
+            write!(f, "Error code: {:#x}", self.error_code)?;
+            match self.vector {
+                10..=13 if self.error_code != 0 => {
+                    write!(f, " ({})", SelectorErrorCode(self.error_code))?
+                }
+                PAGE_FAULT => write!(
+                    f,
+                    " ({:?})\nAccessed address: {:?}",
+                    PageFaultErrorCode::from_bits_truncate(self.error_code),
+                    Cr2::read()
+                )?,
+                _ => {}
+            }
+            writeln!(f)?;
+        }
+
+        writeln!(
+            f,
+            "RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}",
+            self.rax, self.rbx, self.rcx, self.rdx
+        )?;
+        writeln!(
+            f,
+            "RSI={:016x} RDI={:016x} RBP={:016x} RSP={:016x}",
+            self.rsi, self.rdi, self.rbp, self.rsp
+        )?;
+        writeln!(
+            f,
+            "R8 ={:016x} R9 ={:016x} R10={:016x} R11={:016x}",
+            self.r8, self.r9, self.r10, self.r11
+        )?;
+        writeln!(
+            f,
+            "R12={:016x} R13={:016x} R14={:016x} R15={:016x}",
+            self.r12, self.r13, self.r14, self.r15
+        )?;
+        writeln!(
+            f,
+            "RIP={:016x} RFLAGS={:016x} CS={:04x} SS={:04x}",
+            self.rip, self.rflags, self.cs, self.ss
+        )?;
+        writeln!(
+            f,
+            "CR0={:016x} CR2={:016x} CR3={:016x} CR4={:016x}",
+            Cr0::read_raw(),
+            Cr2::read().as_u64(),
+            Cr3::read().0.start_address().as_u64(),
+            Cr4::read_raw()
+        )?;
+
+        write!(f, "{}", percpu::CurrentContext)
+    }
+}
+
+/// The error code of exceptions caused by a segment selector or IDT entry.
+struct SelectorErrorCode(u64);
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let table = match (self.0 >> 1) & 0b11 {
+            0 => "GDT",
+            0b10 => "LDT",
+            _ => "IDT",
+        };
+        write!(f, "{} index {:#x}", table, (self.0 >> 3) & 0x1FFF)?;
+        if self.0 & 1 != 0 {
+            write!(f, ", external event")?;
+        }
+        Ok(())
+    }
+}
+
+/// Points the IDT entries of all architectural exceptions at their entry
+/// points.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    let entry = |vector: usize| VirtAddr::new(unsafe { exception_entries[vector] });
+    unsafe {
+        idt.divide_error.set_handler_addr(entry(0));
+        idt.debug.set_handler_addr(entry(1));
+        idt.non_maskable_interrupt.set_handler_addr(entry(2));
+        idt.breakpoint.set_handler_addr(entry(3));
+        idt.overflow.set_handler_addr(entry(4));
+        idt.bound_range_exceeded.set_handler_addr(entry(5));
+        idt.invalid_opcode.set_handler_addr(entry(6));
+        idt.device_not_available.set_handler_addr(entry(7));
+        idt.double_fault
+            .set_handler_addr(entry(8))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.coprocessor_segment_overrun.set_handler_addr(entry(9));
+        idt.invalid_tss.set_handler_addr(entry(10));
+        idt.segment_not_present.set_handler_addr(entry(11));
+        idt.stack_segment_fault.set_handler_addr(entry(12));
+        idt.general_protection_fault.set_handler_addr(entry(13));
+        idt.page_fault.set_handler_addr(entry(14));
+        idt.x87_floating_point.set_handler_addr(entry(16));
+        idt.alignment_check.set_handler_addr(entry(17));
+        idt.machine_check.set_handler_addr(entry(18));
+        idt.simd_floating_point.set_handler_addr(entry(19));
+        idt.virtualization.set_handler_addr(entry(20));
+        idt.vmm_communication_exception.set_handler_addr(entry(29));
+        idt.security_exception.set_handler_addr(entry(30));
+        // the x86_64 crate still counts these as reserved and doesn't give
+        // access to their entries
+        set_raw_entry(idt, 21, entry(21));
+        set_raw_entry(idt, 28, entry(28));
+    }
+}
+
+/// Writes the entry for `vector` straight into the table, which holds the
+/// entries of all 256 vectors in order.
+///
+/// This function is unsafe because `handler` must be a valid entry point.
+unsafe fn set_raw_entry(idt: &mut InterruptDescriptorTable, vector: usize, handler: VirtAddr) {
+    const _: () = assert!(core::mem::size_of::<InterruptDescriptorTable>() == 256 * 16);
+    let mut raw = Entry::<HandlerFunc>::missing();
+    raw.set_handler_addr(handler);
+    let entries = (idt as *mut InterruptDescriptorTable).cast::<Entry<HandlerFunc>>();
+    entries.add(vector).write(raw);
+}
+
+/// Called by the entry points with interrupts disabled. Returning resumes
+/// the interrupted code.
+#[no_mangle]
+extern "C" fn exception_handler(frame: &mut ExceptionFrame) {
+    interrupts::count_interrupt(frame.vector as u8);
+    match frame.vector {
+        DEBUG | BREAKPOINT => {
+            report(frame);
+            return;
+        }
+        // sent by a crashing CPU to stop this one
+        NON_MASKABLE_INTERRUPT if crash::is_crashing() => crash::park(),
+        PAGE_FAULT => {
+            let addr = Cr2::read();
+            let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+            if memory::address_space::sync_kernel_entry(addr)
+                || memory::address_space::handle_cow_fault(addr, error_code)
+                || memory::lazy::handle_page_fault(addr, error_code)
+            {
+                return;
+            }
+        }
+        _ => {}
+    }
+
+    // the page fault handler runs on the faulting stack, so an overflow
+    // always ends up here as a double fault
+    if frame.vector == DOUBLE_FAULT {
+        let addr = Cr2::read();
+        if memory::stack::is_stack_guard(addr) {
+            crash::crash(
+                &format_args!("{}: kernel stack overflow at {:?}", frame.name(), addr),
+                Some(frame),
+            );
+        }
+    }
+    crash::crash(
+        &format_args!("{} at {:#x}", frame.name(), frame.rip),
+        Some(frame),
+    );
+}
+
+fn report(frame: &ExceptionFrame) {
+    println!("{}", frame);
+    serial_println!("{}", frame);
+}
+
+#[test_case]
+fn test_selector_error_code() {
+    use alloc::format;
+
+    // IDT entry 0x2f, as raised by a missing interrupt handler
+    assert_eq!(
+        format!("{}", SelectorErrorCode(0x2f << 3 | 0b10)),
+        "IDT index 0x2f"
+    );
+    assert_eq!(
+        format!("{}", SelectorErrorCode(0x10 | 0b101)),
+        "LDT index 0x2, external event"
+    );
+}
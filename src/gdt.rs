@@ -1,26 +1,23 @@
+use alloc::boxed::Box;
+use conquer_once::spin::OnceCell;
+use core::ptr::addr_of;
 use generic_once_cell::Lazy;
 use spin::Mutex;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::{structures::tss::TaskStateSegment, VirtAddr};
 
-pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+use crate::memory::stack::KernelStack;
 
-static TSS: Lazy<Mutex<()>, TaskStateSegment> = Lazy::new(|| {
-    let mut tss = TaskStateSegment::new();
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-        const STACK_SIZE: usize = 4096 * 5;
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+const IST_STACK_SIZE: usize = 4096 * 5;
 
-        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-        stack_start + STACK_SIZE
-    };
-    tss
-});
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+static DOUBLE_FAULT_STACK: OnceCell<KernelStack> = OnceCell::uninit();
 
 static GDT: Lazy<Mutex<()>, (GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
     let mut gdt = GlobalDescriptorTable::new();
     let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-    let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*addr_of!(TSS) }));
     (
         gdt,
         Selectors {
@@ -39,9 +36,58 @@ pub fn init() {
     use x86_64::instructions::segmentation::{Segment, CS};
     use x86_64::instructions::tables::load_tss;
 
+    // used until `init_stacks` can map a stack with a guard page
+    static mut BOOT_STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+    unsafe {
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            VirtAddr::from_ptr(addr_of!(BOOT_STACK)) + IST_STACK_SIZE;
+    }
+
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Moves the IST stacks onto kernel stacks with guard pages.
+///
+/// This needs the heap and the frame allocator, so it can only run once
+/// memory is initialized.
+pub fn init_stacks() {
+    DOUBLE_FAULT_STACK.init_once(|| {
+        KernelStack::new(IST_STACK_SIZE).expect("failed to allocate double fault stack")
+    });
+    let top = DOUBLE_FAULT_STACK.get().unwrap().top();
+    unsafe {
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = top;
+    }
+}
+
+/// Gives the calling application processor a GDT and TSS of its own, with a
+/// separate double fault stack.
+///
+/// The tables use the same selectors as the bootstrap processor's, so the
+/// shared IDT works unchanged.
+pub fn init_ap() {
+    use x86_64::instructions::segmentation::{Segment, CS};
+    use x86_64::instructions::tables::load_tss;
+
+    let stack = KernelStack::new(IST_STACK_SIZE).expect("failed to allocate double fault stack");
+    let mut tss = Box::new(TaskStateSegment::new());
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack.top();
+    // the CPU keeps using both for as long as it runs
+    core::mem::forget(stack);
+    let tss: &'static TaskStateSegment = Box::leak(tss);
+
+    let mut gdt = Box::new(GlobalDescriptorTable::new());
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    let gdt: &'static GlobalDescriptorTable = Box::leak(gdt);
+
+    gdt.load();
+    unsafe {
+        CS::set_reg(code_selector);
+        load_tss(tss_selector);
+    }
+}
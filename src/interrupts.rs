@@ -1,65 +1,56 @@
-use crate::gdt;
-use crate::{hlt_loop, println};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{apic, exceptions, irq, smp, smp::percpu::InterruptScope};
 use conquer_once::spin::OnceCell;
 use pic8259::ChainedPics;
 use spin::Mutex;
 use x86_64::{
     instructions::port::Port,
-    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
 };
 
 pub static IDT: OnceCell<Mutex<InterruptDescriptorTable>> = OnceCell::uninit();
 
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_COUNT: AtomicU64 = AtomicU64::new(0);
+static COUNTS: [AtomicU64; 256] = [NO_COUNT; 256];
+
 pub fn init_idt() {
     let mut idt = InterruptDescriptorTable::new();
-    idt.breakpoint.set_handler_fn(breakpoint_handler);
-    idt.page_fault.set_handler_fn(page_fault_handler);
-    unsafe {
-        idt.double_fault
-            .set_handler_fn(double_fault_handler)
-            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-    }
-
-    idt[InterruptIndex::Timer.as_usize()].set_handler_fn(crate::time::timer_interrupt_handler);
-    idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-    idt[InterruptIndex::RealTimeClock.as_usize()]
-        .set_handler_fn(crate::time::rtc_interrupt_handler);
+    exceptions::install(&mut idt);
+    irq::install(&mut idt);
+    idt[apic::lapic::TIMER_VECTOR as usize].set_handler_fn(lapic_timer_interrupt_handler);
+    idt[apic::lapic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
+    idt[apic::lapic::TLB_FLUSH_VECTOR as usize].set_handler_fn(tlb_flush_interrupt_handler);
     IDT.init_once(|| Mutex::new(idt));
+    load_idt();
+}
+
+/// Loads the IDT built by `init_idt` on the current CPU.
+pub fn load_idt() {
     let idt = IDT.get().unwrap().lock();
     let idt: &'static InterruptDescriptorTable = unsafe { core::mem::transmute(&*idt) };
     idt.load();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
-}
-
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
 pub static PICS: Mutex<ChainedPics> =
     Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xA0;
+/// OCW3 that makes the next read of a command port return the in-service
+/// register.
+const PIC_READ_ISR: u8 = 0x0B;
+const PIC_CASCADE_LINE: u8 = 2;
+
+static SPURIOUS_PIC_IRQS: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
@@ -76,18 +67,138 @@ impl InterruptIndex {
     pub fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+
+    /// The ISA IRQ line this interrupt comes in on.
+    pub fn isa_irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
+    }
+}
+
+/// Moves interrupt delivery from the 8259 to the APICs described by the MADT
+/// and registers the timer, keyboard and RTC handlers.
+///
+/// This maps the APICs, so it needs the heap and memory mappers set up. The
+/// 8259 stays in charge if the firmware reports no APIC, with every line but
+/// the cascade masked until a handler is registered for it.
+pub fn init_apic() {
+    if !apic::init() {
+        unsafe { PICS.lock().write_masks(!(1 << 2), 0xFF) };
+    }
+
+    let handlers: [(InterruptIndex, fn(&'static ())); 3] = [
+        (InterruptIndex::Timer, crate::time::timer_irq_handler),
+        (InterruptIndex::Keyboard, keyboard_irq_handler),
+        (InterruptIndex::RealTimeClock, crate::time::rtc_irq_handler),
+    ];
+    for (index, handler) in handlers {
+        irq::register_irq(index.isa_irq(), handler, &()).unwrap();
+    }
+}
+
+/// Checks whether IRQ `line` from the 8259 is spurious. Spurious ones are
+/// counted and must not be handled or acknowledged.
+///
+/// A PIC whose interrupt request goes away before the CPU acknowledges it
+/// still delivers its lowest priority line, IRQ 7 or 15, but without marking
+/// it in service. An EOI for it could end another interrupt in service
+/// instead. A spurious IRQ 15 did come through a real cascade interrupt on
+/// the master though, which gets its EOI here.
+pub fn is_spurious_pic_irq(line: u8) -> bool {
+    if apic::is_enabled() || (line != 7 && line != 15) {
+        return false;
+    }
+
+    let mut pics = PICS.lock();
+    let command = if line < 8 {
+        PIC_1_COMMAND
+    } else {
+        PIC_2_COMMAND
+    };
+    let in_service = unsafe {
+        let mut port = Port::<u8>::new(command);
+        port.write(PIC_READ_ISR);
+        port.read()
+    };
+    if in_service & (1 << (line % 8)) != 0 {
+        return false;
+    }
+
+    if line == 15 {
+        unsafe { pics.notify_end_of_interrupt(PIC_1_OFFSET + PIC_CASCADE_LINE) };
+    }
+    SPURIOUS_PIC_IRQS.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+/// How many spurious IRQ 7s and 15s the 8259 raised since boot.
+pub fn spurious_pic_irq_count() -> u64 {
+    SPURIOUS_PIC_IRQS.load(Ordering::Relaxed)
+}
+
+/// Counts an interrupt or exception on `vector`. Every handler calls this
+/// first, whether it turns out to be spurious or not.
+pub fn count_interrupt(vector: u8) {
+    COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// How often `vector` fired since boot, on all CPUs together.
+pub fn interrupt_count(vector: u8) -> u64 {
+    COUNTS[vector as usize].load(Ordering::Relaxed)
+}
+
+/// Displays what raises interrupts on a vector.
+pub struct VectorName(pub u8);
+
+impl fmt::Display for VectorName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let vector = self.0;
+        let isa = PIC_1_OFFSET..PIC_1_OFFSET + irq::IRQ_LINES as u8;
+        let msi = irq::MSI_VECTOR_BASE..irq::MSI_VECTOR_BASE + irq::MSI_VECTORS as u8;
+        match vector {
+            0..=31 => f.write_str(exceptions::name(vector as u64)),
+            apic::lapic::TIMER_VECTOR => f.write_str("LAPIC timer"),
+            apic::lapic::SPURIOUS_VECTOR => f.write_str("LAPIC spurious"),
+            apic::lapic::TLB_FLUSH_VECTOR => f.write_str("TLB shootdown"),
+            _ if isa.contains(&vector) => write!(f, "IRQ {}", vector - PIC_1_OFFSET),
+            _ if msi.contains(&vector) => f.write_str("MSI"),
+            _ => f.write_str("unused"),
+        }
+    }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+/// Signals the end of the interrupt `vector` to whichever controller
+/// delivered it.
+pub fn notify_end_of_interrupt(vector: u8) {
+    if apic::is_enabled() {
+        apic::lapic::eoi();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(vector) };
+    }
+}
+
+fn keyboard_irq_handler(_: &()) {
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
 
     crate::task::keyboard::add_scancode(scancode);
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+/// Only wakes the CPU up from `hlt`, so that its executor looks for work.
+extern "x86-interrupt" fn lapic_timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _scope = InterruptScope::enter(&stack_frame);
+    count_interrupt(apic::lapic::TIMER_VECTOR);
+    apic::lapic::eoi();
+}
+
+extern "x86-interrupt" fn tlb_flush_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _scope = InterruptScope::enter(&stack_frame);
+    count_interrupt(apic::lapic::TLB_FLUSH_VECTOR);
+    smp::tlb::handle_request();
+    apic::lapic::eoi();
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    count_interrupt(apic::lapic::SPURIOUS_VECTOR);
 }
 
 #[test_case]
@@ -95,3 +206,10 @@ fn test_breakpoint_exception() {
     // invoke a breakpoint exception
     x86_64::instructions::interrupts::int3();
 }
+
+#[test_case]
+fn test_interrupt_count() {
+    let before = interrupt_count(3);
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(interrupt_count(3), before + 1);
+}
@@ -0,0 +1,214 @@
+use alloc::{boxed::Box, vec::Vec};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+};
+
+use crate::{
+    apic,
+    interrupts::{
+        count_interrupt, is_spurious_pic_irq, notify_end_of_interrupt, PICS, PIC_1_OFFSET,
+    },
+    smp::percpu::InterruptScope,
+};
+
+/// The ISA IRQ lines, delivered as vectors `PIC_1_OFFSET..PIC_1_OFFSET + 16`.
+pub const IRQ_LINES: usize = 16;
+/// Vectors for message signaled interrupts, which follow the ISA lines.
+pub const MSI_VECTORS: usize = 16;
+pub const MSI_VECTOR_BASE: u8 = PIC_1_OFFSET + IRQ_LINES as u8;
+
+type Action = Box<dyn Fn() + Send + Sync>;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_ACTIONS: Mutex<Vec<Action>> = Mutex::new(Vec::new());
+/// Handlers of the ISA lines followed by those of the MSI vectors.
+static ACTIONS: [Mutex<Vec<Action>>; IRQ_LINES + MSI_VECTORS] =
+    [NO_ACTIONS; IRQ_LINES + MSI_VECTORS];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    InvalidLine(u8),
+    NoFreeVector,
+    /// Message signaled interrupts are delivered by the local APIC, which
+    /// isn't in use.
+    NoApic,
+}
+
+/// Calls `handler` with `ctx` whenever IRQ `line` fires, and unmasks the
+/// line.
+///
+/// Lines can be shared: every handler registered for a line runs on each of
+/// its interrupts, so handlers have to check whether their device raised it.
+/// The interrupt is acknowledged once all of them ran. Handlers run with
+/// interrupts disabled and must not send an EOI themselves.
+pub fn register_irq<T: Sync>(
+    line: u8,
+    handler: fn(&'static T),
+    ctx: &'static T,
+) -> Result<(), IrqError> {
+    if line as usize >= IRQ_LINES {
+        return Err(IrqError::InvalidLine(line));
+    }
+    let action: Action = Box::new(move || handler(ctx));
+    without_interrupts(|| ACTIONS[line as usize].lock().push(action));
+    unmask(line);
+    Ok(())
+}
+
+/// Allocates a vector for a message signaled interrupt and calls `handler`
+/// with `ctx` whenever it fires.
+///
+/// Returns the vector, which the device still has to be told to send, see
+/// `PciDevice::enable_msi`. Unlike IRQ lines, MSI vectors aren't shared.
+pub fn register_msi<T: Sync>(handler: fn(&'static T), ctx: &'static T) -> Result<u8, IrqError> {
+    if !apic::is_enabled() {
+        return Err(IrqError::NoApic);
+    }
+    let mut action = Some(Box::new(move || handler(ctx)) as Action);
+    without_interrupts(|| {
+        for (index, actions) in ACTIONS[IRQ_LINES..].iter().enumerate() {
+            let mut actions = actions.lock();
+            if actions.is_empty() {
+                actions.push(action.take().unwrap());
+                return Ok(MSI_VECTOR_BASE + index as u8);
+            }
+        }
+        Err(IrqError::NoFreeVector)
+    })
+}
+
+/// Frees an MSI vector allocated by `register_msi`, dropping its handler.
+///
+/// The device must have stopped sending it.
+pub fn unregister_msi(vector: u8) {
+    let index = vector.wrapping_sub(PIC_1_OFFSET) as usize;
+    assert!(
+        (IRQ_LINES..IRQ_LINES + MSI_VECTORS).contains(&index),
+        "invalid MSI vector {vector}"
+    );
+    without_interrupts(|| ACTIONS[index].lock().clear());
+}
+
+/// Stops IRQ `line` from being delivered.
+pub fn mask(line: u8) {
+    set_masked(line, true);
+}
+
+/// Delivers IRQ `line` again after `mask`.
+pub fn unmask(line: u8) {
+    set_masked(line, false);
+}
+
+fn set_masked(line: u8, masked: bool) {
+    assert!((line as usize) < IRQ_LINES, "invalid IRQ line {line}");
+
+    if apic::is_enabled() {
+        if masked {
+            apic::ioapic::set_masked(apic::isa_route(line).gsi, true);
+        } else {
+            apic::route_isa_irq(line, PIC_1_OFFSET + line);
+        }
+        return;
+    }
+
+    without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let mut masks = unsafe { pics.read_masks() };
+        let (chip, bit) = (line as usize / 8, line % 8);
+        if masked {
+            masks[chip] |= 1 << bit;
+        } else {
+            masks[chip] &= !(1 << bit);
+        }
+        unsafe { pics.write_masks(masks[0], masks[1]) };
+    });
+}
+
+/// Points the IDT entries of all IRQ lines and MSI vectors at the
+/// dispatcher.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    let entries: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_LINES + MSI_VECTORS] = [
+        irq_entry::<0>,
+        irq_entry::<1>,
+        irq_entry::<2>,
+        irq_entry::<3>,
+        irq_entry::<4>,
+        irq_entry::<5>,
+        irq_entry::<6>,
+        irq_entry::<7>,
+        irq_entry::<8>,
+        irq_entry::<9>,
+        irq_entry::<10>,
+        irq_entry::<11>,
+        irq_entry::<12>,
+        irq_entry::<13>,
+        irq_entry::<14>,
+        irq_entry::<15>,
+        irq_entry::<16>,
+        irq_entry::<17>,
+        irq_entry::<18>,
+        irq_entry::<19>,
+        irq_entry::<20>,
+        irq_entry::<21>,
+        irq_entry::<22>,
+        irq_entry::<23>,
+        irq_entry::<24>,
+        irq_entry::<25>,
+        irq_entry::<26>,
+        irq_entry::<27>,
+        irq_entry::<28>,
+        irq_entry::<29>,
+        irq_entry::<30>,
+        irq_entry::<31>,
+    ];
+    for (index, entry) in entries.into_iter().enumerate() {
+        idt[PIC_1_OFFSET as usize + index].set_handler_fn(entry);
+    }
+}
+
+/// Dispatches vector `PIC_1_OFFSET + INDEX`, which is an ISA line for the
+/// first `IRQ_LINES` indices and an MSI vector after that. Spurious IRQ 7s
+/// and 15s from the 8259 are dropped.
+extern "x86-interrupt" fn irq_entry<const INDEX: u8>(stack_frame: InterruptStackFrame) {
+    let _scope = InterruptScope::enter(&stack_frame);
+    count_interrupt(PIC_1_OFFSET + INDEX);
+    if is_spurious_pic_irq(INDEX) {
+        return;
+    }
+    for action in ACTIONS[INDEX as usize].lock().iter() {
+        action();
+    }
+    // MSI vectors only exist with the APIC, which doesn't care about the vector
+    notify_end_of_interrupt(PIC_1_OFFSET + INDEX);
+}
+
+#[test_case]
+fn test_register_invalid_line() {
+    fn handler(_: &()) {}
+    assert_eq!(
+        register_irq(IRQ_LINES as u8, handler, &()),
+        Err(IrqError::InvalidLine(16))
+    );
+}
+
+#[test_case]
+fn test_msi_vector() {
+    use core::{
+        arch::asm,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    fn handler(calls: &AtomicUsize) {
+        calls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // the test kernel doesn't set up any devices, so the first vector is free
+    let vector = register_msi(handler, &CALLS).unwrap();
+    assert_eq!(vector, MSI_VECTOR_BASE);
+    unsafe { x86_64::software_interrupt!(MSI_VECTOR_BASE) };
+    assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    unregister_msi(vector);
+}
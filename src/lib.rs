@@ -4,6 +4,7 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 #![allow(clippy::missing_safety_doc)]
 #![allow(clippy::new_without_default)]
 
@@ -11,13 +12,19 @@ extern crate alloc;
 
 pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod backtrace;
+pub mod crash;
 pub mod drivers;
+pub mod exceptions;
 pub mod gdt;
 pub mod interrupts;
+pub mod irq;
 pub mod memory;
 pub mod networking;
 pub mod pci;
 pub mod serial;
+pub mod smp;
 pub mod task;
 pub mod time;
 pub mod vga_buffer;
@@ -28,7 +35,7 @@ use core::panic::PanicInfo;
 use bootloader::entry_point;
 use bootloader::BootInfo;
 use memory::{BootInfoFrameAllocator, FRAME_ALLOCATOR, MAPPER};
-use spin::Mutex;
+use smp::lock::CpuMutex;
 use task::keyboard;
 use x86_64::VirtAddr;
 
@@ -61,6 +68,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    backtrace::print();
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
@@ -98,21 +106,32 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
 pub fn init(boot_info: &'static BootInfo) {
     gdt::init();
     interrupts::init_idt();
+    crash::init();
     time::set_pit_frequency_divider(time::PIT_DIVIDER as u16, 0);
     unsafe {
         let mut pics = interrupts::PICS.lock();
         pics.initialize();
-        pics.write_masks(0, 0)
+        // masked until `interrupts::init_apic` decides who delivers interrupts
+        pics.disable();
     };
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    memory::regions::init(&boot_info.memory_map);
+    memory::protection::init(&mut mapper);
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    smp::reserve_trampoline(&mut frame_allocator);
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    backtrace::symbols::init();
 
-    MAPPER.init_once(|| mapper);
-    FRAME_ALLOCATOR.init_once(|| Mutex::new(frame_allocator));
+    MAPPER.init_once(|| CpuMutex::new(mapper));
+    FRAME_ALLOCATOR.init_once(|| CpuMutex::new(frame_allocator));
+    smp::percpu::init(0);
+
+    interrupts::init_apic();
+
+    gdt::init_stacks();
 
     //read_acpi();
 
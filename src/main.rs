@@ -6,14 +6,19 @@
 
 extern crate alloc;
 
-use alloc::vec;
+use alloc::{boxed::Box, vec};
+use blog_os::drivers::{
+    ata::{AtaDrive, Channel, Drive},
+    block::BlockDevice,
+};
+use blog_os::memory::swap;
 use blog_os::networking::add_interface;
 use blog_os::networking::socket::SOCKETS;
 use blog_os::task::executor::spawn;
 use blog_os::task::network::pump_interfaces;
 use blog_os::task::{executor::Executor, keyboard, shell::shell, Task};
 use blog_os::time::sleep;
-use blog_os::{pci, println};
+use blog_os::{pci, println, smp};
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use core::time::Duration;
@@ -26,6 +31,12 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("Starting up");
 
     blog_os::init(boot_info); // new
+    smp::init();
+    println!(
+        "CPUs: {} of {} online",
+        smp::cpus().iter().filter(|cpu| cpu.is_online()).count(),
+        smp::cpus().len()
+    );
 
     pci::scan_devices();
 
@@ -34,15 +45,21 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     add_interface(rtl).unwrap();
 
     let ide = pci::get_device(0x8086, 0x7010).unwrap();
-    println!("Prog IF: {:b}", ide.prog);
+    // the secondary master is reserved for swap, the primary channel holds
+    // the boot disk and the shared folder
+    match AtaDrive::new(&ide, Channel::Secondary, Drive::Master) {
+        Some(drive) => {
+            println!("Swap: {} KiB", drive.sector_count() / 2);
+            swap::init(Box::new(drive));
+        }
+        None => println!("Swap: no disk on the secondary master"),
+    }
 
     SOCKETS.init_once(|| Mutex::new(SocketSet::new(vec![])));
 
     #[cfg(test)]
     test_main();
 
-    //ata
-
     let mut executor = Executor::new();
     executor.spawn(Task::new(keyboard::forward_keys()));
     executor.spawn(Task::new(shell()));
@@ -57,8 +74,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
-    loop {}
+    blog_os::crash::panic(info)
 }
 
 #[cfg(test)]
@@ -0,0 +1,722 @@
+use conquer_once::spin::OnceCell;
+use x86_64::{
+    registers::control::{Cr3, Cr3Flags},
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{
+        mapper::{MapToError, MapperFlush},
+        page_table::PageTableEntry,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{
+    lazy::{self, RegionError},
+    phys_to_virt, swap, BootInfoFrameAllocator, FRAME_ALLOCATOR, PHYSICAL_MEMORY_OFFSET,
+};
+use crate::smp::tlb;
+
+/// Start of the address range each address space owns privately.
+///
+/// Everything outside of it belongs to the kernel and is shared between all
+/// address spaces. The kernel itself lives in the lower half, so user space is
+/// a window of level 4 entries the kernel never maps.
+pub const USER_START: u64 = 0x_0000_1000_0000_0000;
+pub const USER_END: u64 = 0x_0000_4000_0000_0000;
+
+/// Flags of the page tables leading to user pages.
+const USER_TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+/// Marks a read-only user page whose frame is shared copy-on-write.
+const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+static KERNEL_L4: OnceCell<PhysFrame> = OnceCell::uninit();
+
+/// Records the active level 4 table as the kernel's.
+pub(super) fn init() {
+    KERNEL_L4.init_once(|| Cr3::read().0);
+}
+
+fn is_user_entry(index: usize) -> bool {
+    let start = VirtAddr::new(USER_START).p4_index();
+    let end = VirtAddr::new(USER_END).p4_index();
+    (usize::from(start)..usize::from(end)).contains(&index)
+}
+
+/// Returns the page table stored in `frame`.
+///
+/// This function is unsafe because the frame must hold a page table and the
+/// caller must not create aliasing `&mut` references.
+unsafe fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// A set of page tables sharing the kernel mappings and owning a private
+/// user range between `USER_START` and `USER_END`.
+pub struct AddressSpace {
+    l4_frame: PhysFrame,
+    mapper: OffsetPageTable<'static>,
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty user range.
+    pub fn new() -> Option<Self> {
+        let l4_frame = FRAME_ALLOCATOR.get()?.lock().allocate_frame()?;
+        let kernel = unsafe { table_at(*KERNEL_L4.get()?) };
+        let table = unsafe { table_at(l4_frame) };
+        table.zero();
+        for (index, entry) in kernel.iter().enumerate() {
+            assert!(
+                !is_user_entry(index) || entry.is_unused(),
+                "kernel mapping inside the user range"
+            );
+            table[index] = entry.clone();
+        }
+
+        let offset = VirtAddr::new(unsafe { PHYSICAL_MEMORY_OFFSET });
+        Some(Self {
+            l4_frame,
+            mapper: unsafe { OffsetPageTable::new(table, offset) },
+        })
+    }
+
+    /// Maps `size` bytes at the page aligned `start` in the user range to
+    /// zeroed frames, each allocated when the page is first touched.
+    pub fn map_user(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), RegionError> {
+        check_user_range(start, size);
+        lazy::register_user_region(self.l4_frame, start, size, flags)
+    }
+
+    /// Unmaps `size` bytes at the page aligned `start` in the user range and
+    /// frees the frames.
+    pub fn unmap_user(&mut self, start: VirtAddr, size: u64) -> Result<(), RegionError> {
+        check_user_range(start, size);
+        lazy::unmap_user_range(self.l4_frame, start, start + size)
+    }
+
+    /// Duplicates the address space, sharing every user page copy-on-write.
+    ///
+    /// Writable pages become read-only in both address spaces and are only
+    /// copied once either side writes to them. Swapped out pages are read
+    /// back into a private copy for the child.
+    pub fn fork(&mut self) -> Option<AddressSpace> {
+        let mut child = AddressSpace::new()?;
+        lazy::copy_user_regions(self.l4_frame, child.l4_frame).ok()?;
+        // keeps pages of the parent from being faulted in during the walk
+        let mapper = super::MAPPER.get().unwrap().lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+
+        let mut failed = false;
+        self.for_each_user_mapping(|addr, entry, size| {
+            if failed {
+                return;
+            }
+            let mapped = if let Some(slot) = swap::swap_slot(entry) {
+                child.copy_swapped_page(addr, entry, slot, &mut frame_allocator)
+            } else if size == Size4KiB::SIZE {
+                child.share_page(addr, entry, &mut frame_allocator)
+            } else if size == Size2MiB::SIZE {
+                child.copy_huge_page(addr, entry, &mut frame_allocator)
+            } else {
+                // nothing maps 1 GiB pages into user space
+                false
+            };
+            failed = !mapped;
+        });
+
+        // the parent may be active on other CPUs, which have to see the pages
+        // turn read-only
+        tlb::flush_all();
+        // dropping the child releases whatever it already shares
+        drop(frame_allocator);
+        drop(mapper);
+        (!failed).then_some(child)
+    }
+
+    /// Maps the 4 KiB page at `addr` to the frame of the parent's `entry`,
+    /// making both read-only and copy-on-write if it was writable.
+    fn share_page(
+        &mut self,
+        addr: VirtAddr,
+        entry: &mut PageTableEntry,
+        frame_allocator: &mut BootInfoFrameAllocator,
+    ) -> bool {
+        let mut flags = entry.flags();
+        if flags.contains(PageTableFlags::WRITABLE) {
+            flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+            entry.set_flags(flags);
+        }
+
+        let frame = entry.frame().unwrap();
+        if !frame_allocator.share_frame(frame) {
+            return false;
+        }
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let result = unsafe {
+            self.mapper.map_to_with_table_flags(
+                page,
+                frame,
+                flags,
+                USER_TABLE_FLAGS,
+                frame_allocator,
+            )
+        };
+        match result {
+            Ok(flush) => {
+                flush.ignore();
+                true
+            }
+            Err(_) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                false
+            }
+        }
+    }
+
+    /// Maps a private copy of the parent's page at `addr`, read back from the
+    /// swap `slot` the parent's `entry` points to.
+    fn copy_swapped_page(
+        &mut self,
+        addr: VirtAddr,
+        entry: &PageTableEntry,
+        slot: u64,
+        frame_allocator: &mut BootInfoFrameAllocator,
+    ) -> bool {
+        let Some(copy) = frame_allocator.allocate_frame() else {
+            return false;
+        };
+        if !swap::lock().unwrap().read(slot, copy) {
+            unsafe { frame_allocator.deallocate_frame(copy) };
+            return false;
+        }
+
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let result = unsafe {
+            self.mapper.map_to_with_table_flags(
+                page,
+                copy,
+                swap::swapped_flags(entry),
+                USER_TABLE_FLAGS,
+                frame_allocator,
+            )
+        };
+        match result {
+            Ok(flush) => {
+                flush.ignore();
+                true
+            }
+            Err(_) => {
+                unsafe { frame_allocator.deallocate_frame(copy) };
+                false
+            }
+        }
+    }
+
+    /// Maps a private copy of the parent's 2 MiB page at `addr`.
+    ///
+    /// Huge pages are copied right away, copy-on-write only works on 4 KiB
+    /// pages.
+    fn copy_huge_page(
+        &mut self,
+        addr: VirtAddr,
+        entry: &PageTableEntry,
+        frame_allocator: &mut BootInfoFrameAllocator,
+    ) -> bool {
+        let Some(copy) = frame_allocator.allocate_huge_frame() else {
+            return false;
+        };
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                phys_to_virt(entry.addr()).as_ptr::<u8>(),
+                phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+                Size2MiB::SIZE as usize,
+            );
+        }
+
+        let page = Page::<Size2MiB>::containing_address(addr);
+        let result = unsafe {
+            self.mapper.map_to_with_table_flags(
+                page,
+                copy,
+                entry.flags(),
+                USER_TABLE_FLAGS,
+                frame_allocator,
+            )
+        };
+        match result {
+            Ok(flush) => {
+                flush.ignore();
+                true
+            }
+            Err(_) => {
+                unsafe { free_frames(copy.start_address(), Size2MiB::SIZE, frame_allocator) };
+                false
+            }
+        }
+    }
+
+    /// Calls `f` with the address, entry and size of every page mapped in
+    /// the user range, huge pages included.
+    fn for_each_user_mapping(&mut self, mut f: impl FnMut(VirtAddr, &mut PageTableEntry, u64)) {
+        let table = self.mapper.level_4_table();
+        for (index, entry) in table.iter_mut().enumerate() {
+            if is_user_entry(index) && !entry.is_unused() {
+                let base = (index as u64) << 39;
+                unsafe { walk_leaves(table_at(entry.frame().unwrap()), 3, base, &mut f) };
+            }
+        }
+    }
+
+    pub fn mapper(&mut self) -> &mut OffsetPageTable<'static> {
+        &mut self.mapper
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.l4_frame
+    }
+
+    /// Loads this address space into CR3.
+    ///
+    /// This function is unsafe because anything the running code references
+    /// in the user range of the current address space disappears.
+    pub unsafe fn activate(&self) {
+        Cr3::write(self.l4_frame, Cr3Flags::empty());
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            unsafe { switch_to_kernel() };
+        }
+
+        lazy::remove_user_regions(self.l4_frame);
+        let mut frame_allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+        let table = self.mapper.level_4_table();
+        for (index, entry) in table.iter_mut().enumerate() {
+            if is_user_entry(index) && !entry.is_unused() {
+                unsafe { free_table(entry, 3, &mut *frame_allocator) };
+            }
+        }
+        unsafe { frame_allocator.deallocate_frame(self.l4_frame) };
+    }
+}
+
+/// Frees the table `entry` points to, all tables below it and every frame
+/// mapped through them, huge pages included, then clears the entry. Swap
+/// slots of swapped out pages are released.
+unsafe fn free_table(
+    entry: &mut PageTableEntry,
+    level: u8,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let frame = entry.frame().unwrap();
+    for child in table_at(frame).iter_mut().filter(|e| !e.is_unused()) {
+        if let Some(slot) = swap::swap_slot(child) {
+            swap::lock().unwrap().release(slot);
+            child.set_unused();
+        } else if level > 1 && !child.flags().contains(PageTableFlags::HUGE_PAGE) {
+            free_table(child, level - 1, frame_allocator);
+        } else {
+            free_frames(child.addr(), page_size(level), frame_allocator);
+            child.set_unused();
+        }
+    }
+    frame_allocator.deallocate_frame(frame);
+    entry.set_unused();
+}
+
+/// Frees the 4 KiB frames making up the `size` bytes at `start`.
+unsafe fn free_frames(
+    start: PhysAddr,
+    size: u64,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let first = PhysFrame::containing_address(start);
+    for frame in PhysFrame::range(first, first + size / Size4KiB::SIZE) {
+        frame_allocator.deallocate_frame(frame);
+    }
+}
+
+/// Size of the page mapped by an entry of a level `level` table.
+fn page_size(level: u8) -> u64 {
+    Size4KiB::SIZE << (9 * (level as u64 - 1))
+}
+
+/// Calls `f` with the address, entry and page size of every mapping below a
+/// level `level` table that covers the addresses from `base`.
+unsafe fn walk_leaves(
+    table: &mut PageTable,
+    level: u8,
+    base: u64,
+    f: &mut impl FnMut(VirtAddr, &mut PageTableEntry, u64),
+) {
+    for (index, entry) in table.iter_mut().enumerate() {
+        if entry.is_unused() {
+            continue;
+        }
+        let addr = base + index as u64 * page_size(level);
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            f(VirtAddr::new(addr), entry, page_size(level));
+        } else {
+            walk_leaves(table_at(entry.frame().unwrap()), level - 1, addr, f);
+        }
+    }
+}
+
+/// Returns the level 1 entry for `addr` in the tables rooted at `l4_frame`.
+unsafe fn leaf_entry(l4_frame: PhysFrame, addr: VirtAddr) -> Option<&'static mut PageTableEntry> {
+    let mut table = table_at(l4_frame);
+    for index in [addr.p4_index(), addr.p3_index(), addr.p2_index()] {
+        let entry = &table[index];
+        if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = table_at(entry.frame().ok()?);
+    }
+    Some(&mut table[addr.p1_index()])
+}
+
+/// Returns the table a present, non-huge entry points to.
+unsafe fn next_table(entry: &PageTableEntry) -> Option<&'static mut PageTable> {
+    let flags = entry.flags();
+    (flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::HUGE_PAGE))
+        .then(|| table_at(entry.frame().unwrap()))
+}
+
+/// Frees the level 1 and level 2 tables covering the kernel range from
+/// `start` to `end` that no longer map anything.
+///
+/// Level 3 tables stay, since every address space links to them through its
+/// copy of the kernel's level 4 entries. The caller must hold the `MAPPER`
+/// lock.
+pub(super) unsafe fn free_empty_kernel_tables(
+    start: VirtAddr,
+    end: VirtAddr,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let is_empty = |table: &PageTable| table.iter().all(PageTableEntry::is_unused);
+    let l4 = table_at(*KERNEL_L4.get().unwrap());
+    let mut addr = start.align_down(Size1GiB::SIZE);
+    while addr < end {
+        let next = addr + Size1GiB::SIZE;
+        let Some(l3) = next_table(&l4[addr.p4_index()]) else {
+            addr = next;
+            continue;
+        };
+        let l3_entry = &mut l3[addr.p3_index()];
+        if let Some(l2) = next_table(l3_entry) {
+            let first = start.max(addr).p2_index();
+            let last = (end.min(next) - 1u64).p2_index();
+            for l2_entry in &mut l2[usize::from(first)..=usize::from(last)] {
+                if next_table(l2_entry).is_some_and(|l1| is_empty(l1)) {
+                    free_kernel_table(l2_entry, frame_allocator);
+                }
+            }
+            if is_empty(l2) {
+                free_kernel_table(l3_entry, frame_allocator);
+            }
+        }
+        addr = next;
+    }
+}
+
+/// Unlinks the table `entry` points to and frees it once no CPU caches it
+/// anymore.
+unsafe fn free_kernel_table(
+    entry: &mut PageTableEntry,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let frame = entry.frame().unwrap();
+    entry.set_unused();
+    tlb::flush_all();
+    frame_allocator.deallocate_frame(frame);
+}
+
+/// The kernel's level 4 table.
+pub(super) fn kernel_root() -> PhysFrame {
+    *KERNEL_L4.get().unwrap()
+}
+
+/// Returns the level 1 entry for `addr` in the tables rooted at `root`, the
+/// kernel's or those of a live address space, if the tables leading to it
+/// exist.
+pub(super) fn leaf_entry_in(
+    root: PhysFrame,
+    addr: VirtAddr,
+) -> Option<&'static mut PageTableEntry> {
+    unsafe { leaf_entry(root, addr) }
+}
+
+/// Maps the user `page` to `frame` in the tables rooted at `root`.
+///
+/// This function is unsafe because `root` must be the level 4 table of a live
+/// address space, and the caller must hold the `MAPPER` lock, which the page
+/// fault handler takes before changing any tables.
+pub(super) unsafe fn map_user_page(
+    root: PhysFrame,
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<MapperFlush<Size4KiB>, MapToError<Size4KiB>> {
+    let offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET);
+    let mut mapper = OffsetPageTable::new(table_at(root), offset);
+    mapper.map_to_with_table_flags(page, frame, flags, USER_TABLE_FLAGS, frame_allocator)
+}
+
+/// Whether `addr` is mapped in the active address space.
+///
+/// This takes no locks, so it is safe to use while crashing.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    let mut table = unsafe { table_at(Cr3::read().0) };
+    for index in [addr.p4_index(), addr.p3_index(), addr.p2_index()] {
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return false;
+        }
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return true;
+        }
+        table = unsafe { table_at(entry.frame().unwrap()) };
+    }
+    table[addr.p1_index()]
+        .flags()
+        .contains(PageTableFlags::PRESENT)
+}
+
+/// Resolves a write to a copy-on-write page of the active address space.
+///
+/// The last address space holding the frame gets it back writable, everyone
+/// else gets a private copy. Returns whether the fault was handled.
+pub fn handle_cow_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let write_to_present =
+        PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if !error_code.contains(write_to_present) || !(USER_START..USER_END).contains(&addr.as_u64()) {
+        return false;
+    }
+
+    let Some(entry) = (unsafe { leaf_entry(Cr3::read().0, addr) }) else {
+        return false;
+    };
+    if entry.flags().contains(PageTableFlags::WRITABLE) {
+        // another CPU sharing the address space got here first
+        return true;
+    }
+    if !entry.flags().contains(COPY_ON_WRITE) {
+        return false;
+    }
+    let Some(frame_allocator) = FRAME_ALLOCATOR.get() else {
+        return false;
+    };
+    // the interrupted code would never get to unlock it
+    if frame_allocator.is_locked_here() {
+        return false;
+    }
+    let mut frame_allocator = frame_allocator.lock();
+
+    let flags = (entry.flags() - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+    let frame = entry.frame().unwrap();
+    if frame_allocator.ref_count(frame) > 1 {
+        let Some(copy) = frame_allocator.allocate_frame() else {
+            return false;
+        };
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+                phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+                4096,
+            );
+            entry.set_frame(copy, flags);
+            tlb::flush(addr);
+            frame_allocator.deallocate_frame(frame);
+        }
+    } else {
+        entry.set_flags(flags);
+        tlb::flush(addr);
+    }
+    true
+}
+
+fn check_user_range(start: VirtAddr, size: u64) {
+    assert!(
+        start.as_u64() >= USER_START && start.as_u64() + size <= USER_END,
+        "range outside of user space"
+    );
+    assert!(start.is_aligned(4096u64), "user range must be page aligned");
+}
+
+/// Switches back to the kernel's own page tables.
+///
+/// This function is unsafe for the same reasons as `AddressSpace::activate`.
+pub unsafe fn switch_to_kernel() {
+    Cr3::write(*KERNEL_L4.get().unwrap(), Cr3Flags::empty());
+}
+
+/// Copies a kernel level 4 entry that was created after the active address
+/// space, so kernel mappings added later are visible everywhere.
+///
+/// Called from the page fault handler; returns whether anything was copied.
+pub fn sync_kernel_entry(addr: VirtAddr) -> bool {
+    let index = addr.p4_index();
+    let Some(&kernel_frame) = KERNEL_L4.get() else {
+        return false;
+    };
+    let active_frame = Cr3::read().0;
+    if is_user_entry(usize::from(index)) || active_frame == kernel_frame {
+        return false;
+    }
+
+    let kernel = unsafe { table_at(kernel_frame) };
+    let active = unsafe { table_at(active_frame) };
+    if kernel[index].is_unused() || !active[index].is_unused() {
+        return false;
+    }
+    active[index] = kernel[index].clone();
+    true
+}
+
+#[test_case]
+fn test_address_space() {
+    use x86_64::structures::paging::Translate;
+
+    let free = FRAME_ALLOCATOR.get().unwrap().lock().free_frames();
+    let addr = VirtAddr::new(USER_START);
+    let mut space = AddressSpace::new().unwrap();
+    space
+        .map_user(addr, 4096, PageTableFlags::WRITABLE)
+        .unwrap();
+    assert!(space.mapper().translate_addr(addr).is_none());
+
+    unsafe {
+        space.activate();
+        addr.as_mut_ptr::<u64>().write_volatile(7);
+        assert_eq!(addr.as_ptr::<u64>().read_volatile(), 7);
+        switch_to_kernel();
+    }
+    assert!(space.mapper().translate_addr(addr).is_some());
+    assert!(super::MAPPER
+        .get()
+        .unwrap()
+        .lock()
+        .translate_addr(addr)
+        .is_none());
+
+    drop(space);
+    assert_eq!(FRAME_ALLOCATOR.get().unwrap().lock().free_frames(), free);
+}
+
+#[test_case]
+fn test_unmap_user() {
+    use x86_64::structures::paging::Translate;
+
+    let free = FRAME_ALLOCATOR.get().unwrap().lock().free_frames();
+    let addr = VirtAddr::new(USER_START);
+    let pages = [addr, addr + 4096u64, addr + 2 * 4096u64];
+    let mut space = AddressSpace::new().unwrap();
+    space
+        .map_user(addr, 3 * 4096, PageTableFlags::WRITABLE)
+        .unwrap();
+    assert_eq!(
+        space.map_user(addr + 4096u64, 4096, PageTableFlags::WRITABLE),
+        Err(RegionError::Overlap)
+    );
+
+    unsafe {
+        space.activate();
+        for page in pages {
+            assert_eq!(page.as_ptr::<u64>().read_volatile(), 0);
+            page.as_mut_ptr::<u64>().write_volatile(1);
+        }
+        // cuts the region in two, the pages on either side stay
+        space.unmap_user(pages[1], 4096).unwrap();
+        assert_eq!(pages[0].as_ptr::<u64>().read_volatile(), 1);
+        assert_eq!(pages[2].as_ptr::<u64>().read_volatile(), 1);
+        switch_to_kernel();
+    }
+    assert!(space.mapper().translate_addr(pages[1]).is_none());
+
+    // the hole can be mapped again and starts out zeroed
+    space
+        .map_user(pages[1], 4096, PageTableFlags::WRITABLE)
+        .unwrap();
+    unsafe {
+        space.activate();
+        assert_eq!(pages[1].as_ptr::<u64>().read_volatile(), 0);
+        switch_to_kernel();
+    }
+
+    drop(space);
+    assert_eq!(FRAME_ALLOCATOR.get().unwrap().lock().free_frames(), free);
+}
+
+#[test_case]
+fn test_copy_on_write() {
+    let free = FRAME_ALLOCATOR.get().unwrap().lock().free_frames();
+    let addr = VirtAddr::new(USER_START);
+    let mut parent = AddressSpace::new().unwrap();
+    parent
+        .map_user(addr, 4096, PageTableFlags::WRITABLE)
+        .unwrap();
+
+    unsafe {
+        parent.activate();
+        addr.as_mut_ptr::<u64>().write_volatile(1);
+        let child = parent.fork().unwrap();
+        // the parent's write copies the frame, the child keeps the old one
+        addr.as_mut_ptr::<u64>().write_volatile(2);
+        child.activate();
+        assert_eq!(addr.as_ptr::<u64>().read_volatile(), 1);
+        addr.as_mut_ptr::<u64>().write_volatile(3);
+        parent.activate();
+        assert_eq!(addr.as_ptr::<u64>().read_volatile(), 2);
+        switch_to_kernel();
+    }
+
+    drop(parent);
+    assert_eq!(FRAME_ALLOCATOR.get().unwrap().lock().free_frames(), free);
+}
+
+#[test_case]
+fn test_fork_huge_page() {
+    use super::huge::{map_anonymous, HUGE_PAGE_SIZE};
+
+    let free = FRAME_ALLOCATOR.get().unwrap().lock().free_frames();
+    let addr = VirtAddr::new(USER_START);
+    let mut parent = AddressSpace::new().unwrap();
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let mut frame_allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    map_anonymous(
+        parent.mapper(),
+        addr,
+        HUGE_PAGE_SIZE,
+        flags,
+        &mut frame_allocator,
+    )
+    .unwrap();
+    drop(frame_allocator);
+
+    unsafe {
+        parent.activate();
+        addr.as_mut_ptr::<u64>().write_volatile(1);
+        let child = parent.fork().unwrap();
+        addr.as_mut_ptr::<u64>().write_volatile(2);
+        child.activate();
+        assert_eq!(addr.as_ptr::<u64>().read_volatile(), 1);
+        parent.activate();
+        assert_eq!(addr.as_ptr::<u64>().read_volatile(), 2);
+        switch_to_kernel();
+    }
+
+    drop(parent);
+    assert_eq!(FRAME_ALLOCATOR.get().unwrap().lock().free_frames(), free);
+}
@@ -0,0 +1,110 @@
+use core::{
+    ops::{Deref, DerefMut},
+    slice,
+};
+
+use x86_64::{structures::paging::frame::PhysFrameRange, PhysAddr, VirtAddr};
+
+use super::{lazy, phys_to_virt, FRAME_ALLOCATOR};
+
+/// Placement requirements for a DMA buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct DmaConstraints {
+    /// Alignment of the physical start address in bytes.
+    pub align: u64,
+    /// Highest physical address the buffer may cover.
+    pub max_addr: u64,
+}
+
+impl DmaConstraints {
+    pub const ANY: Self = Self {
+        align: 4096,
+        max_addr: u64::MAX,
+    };
+
+    /// For devices that can only address the first 4 GiB.
+    pub const ZONE_32BIT: Self = Self {
+        align: 4096,
+        max_addr: u32::MAX as u64,
+    };
+
+    pub const fn aligned(self, align: u64) -> Self {
+        Self { align, ..self }
+    }
+}
+
+/// A zeroed, physically contiguous buffer a device can access directly.
+///
+/// The frames come straight from `FRAME_ALLOCATOR` and are accessed through
+/// the physical memory mapping. They are returned when the buffer is dropped.
+pub struct DmaBuffer {
+    frames: PhysFrameRange,
+    len: usize,
+}
+
+impl DmaBuffer {
+    pub fn new(len: usize) -> Option<Self> {
+        Self::with_constraints(len, DmaConstraints::ANY)
+    }
+
+    pub fn with_constraints(len: usize, constraints: DmaConstraints) -> Option<Self> {
+        let count = (len + 4095) / 4096;
+        lazy::reserve_frames(count);
+        let frames = FRAME_ALLOCATOR
+            .get()?
+            .lock()
+            .allocate_contiguous_constrained(count, constraints.align, constraints.max_addr)?;
+
+        let mut buffer = Self { frames, len };
+        buffer.fill(0);
+        Some(buffer)
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.frames.start.start_address()
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        phys_to_virt(self.phys_addr())
+    }
+}
+
+impl Deref for DmaBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.virt_addr().as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for DmaBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.virt_addr().as_mut_ptr(), self.len) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        let mut frame_allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+        unsafe { frame_allocator.deallocate_contiguous(self.frames) };
+    }
+}
+
+#[test_case]
+fn test_dma_constraints() {
+    let free = FRAME_ALLOCATOR.get().unwrap().lock().free_frames();
+    let constraints = DmaConstraints::ZONE_32BIT.aligned(64 * 1024);
+    let mut buffer = DmaBuffer::with_constraints(3 * 4096, constraints).unwrap();
+
+    let phys = buffer.phys_addr().as_u64();
+    assert_eq!(phys % (64 * 1024), 0);
+    assert!(phys + 3 * 4096 - 1 <= u32::MAX as u64);
+    assert!(buffer.iter().all(|&b| b == 0));
+
+    buffer[4096] = 0xAB;
+    let byte: *const u8 = phys_to_virt(PhysAddr::new(phys + 4096)).as_ptr();
+    assert_eq!(unsafe { *byte }, 0xAB);
+
+    drop(buffer);
+    assert_eq!(FRAME_ALLOCATOR.get().unwrap().lock().free_frames(), free);
+}
@@ -0,0 +1,205 @@
+use x86_64::{
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame,
+        Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::BootInfoFrameAllocator;
+use crate::smp::tlb;
+
+pub const HUGE_PAGE_SIZE: u64 = Size2MiB::SIZE;
+
+/// Maps `size` bytes of physical memory starting at `phys` to `virt`.
+///
+/// Every 2 MiB chunk where both addresses are 2 MiB aligned is mapped with a
+/// single huge page, the rest with 4 KiB pages.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// physical range may be mapped with the given flags.
+pub unsafe fn map_range<M, A>(
+    mapper: &mut M,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    frame_allocator: &mut A,
+) -> Result<(), MapToError<Size4KiB>>
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB>,
+    A: FrameAllocator<Size4KiB>,
+{
+    assert!(virt.is_aligned(Size4KiB::SIZE) && phys.is_aligned(Size4KiB::SIZE));
+
+    let mut offset = 0;
+    while offset < size {
+        let (virt, phys) = (virt + offset, phys + offset);
+        if fits_huge_page(virt, phys, size - offset) {
+            let page = Page::<Size2MiB>::containing_address(virt);
+            let frame = PhysFrame::containing_address(phys);
+            mapper
+                .map_to(page, frame, flags, frame_allocator)
+                .map_err(huge_map_error)?
+                .flush();
+            offset += HUGE_PAGE_SIZE;
+        } else {
+            let page = Page::<Size4KiB>::containing_address(virt);
+            let frame = PhysFrame::containing_address(phys);
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+            offset += Size4KiB::SIZE;
+        }
+    }
+    Ok(())
+}
+
+/// Maps `size` bytes at `virt` to newly allocated frames.
+///
+/// Uses a 2 MiB frame for every aligned 2 MiB chunk as long as the allocator
+/// has free 2 MiB runs left, and 4 KiB frames otherwise. The frames are not
+/// zeroed.
+pub fn map_anonymous<M>(
+    mapper: &mut M,
+    virt: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<(), MapToError<Size4KiB>>
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB>,
+{
+    assert!(virt.is_aligned(Size4KiB::SIZE));
+
+    let mut offset = 0;
+    let mut huge_frames = true;
+    while offset < size {
+        let virt = virt + offset;
+        if huge_frames && virt.is_aligned(HUGE_PAGE_SIZE) && size - offset >= HUGE_PAGE_SIZE {
+            if let Some(frame) = frame_allocator.allocate_huge_frame() {
+                let page = Page::<Size2MiB>::containing_address(virt);
+                unsafe { mapper.map_to(page, frame, flags, frame_allocator) }
+                    .map_err(huge_map_error)?
+                    .flush();
+                offset += HUGE_PAGE_SIZE;
+                continue;
+            }
+            huge_frames = false;
+        }
+
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let page = Page::<Size4KiB>::containing_address(virt);
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator) }?.flush();
+        offset += Size4KiB::SIZE;
+    }
+    Ok(())
+}
+
+/// Removes every mapping in the `size` bytes starting at `virt`, whichever
+/// page size it uses.
+pub fn unmap_range<M>(mapper: &mut M, virt: VirtAddr, size: u64)
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB> + Translate,
+{
+    unmap_each(mapper, virt, size, |_| ());
+}
+
+/// Unmaps a range mapped by `map_anonymous` and frees its frames.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// frames are no longer used through any other mapping.
+pub unsafe fn unmap_anonymous<M>(
+    mapper: &mut M,
+    virt: VirtAddr,
+    size: u64,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB> + Translate,
+{
+    unmap_each(mapper, virt, size, |frame| {
+        let start = PhysFrame::containing_address(frame.start_address());
+        for frame in PhysFrame::range(start, start + frame.size() / Size4KiB::SIZE) {
+            frame_allocator.deallocate_frame(frame);
+        }
+    });
+}
+
+fn unmap_each<M>(mapper: &mut M, virt: VirtAddr, size: u64, mut f: impl FnMut(MappedFrame))
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB> + Translate,
+{
+    let end = virt + size;
+    let mut addr = virt.align_down(Size4KiB::SIZE);
+    while addr < end {
+        let TranslateResult::Mapped { frame, .. } = mapper.translate(addr) else {
+            addr += Size4KiB::SIZE;
+            continue;
+        };
+        match frame {
+            MappedFrame::Size2MiB(_) => {
+                let page = Page::<Size2MiB>::containing_address(addr);
+                if let Ok((_, flush)) = Mapper::<Size2MiB>::unmap(mapper, page) {
+                    flush.ignore();
+                    tlb::flush(page.start_address());
+                    f(frame);
+                }
+                addr = page.start_address() + HUGE_PAGE_SIZE;
+            }
+            _ => {
+                let page = Page::<Size4KiB>::containing_address(addr);
+                if let Ok((_, flush)) = Mapper::<Size4KiB>::unmap(mapper, page) {
+                    flush.ignore();
+                    tlb::flush(page.start_address());
+                    f(frame);
+                }
+                addr += Size4KiB::SIZE;
+            }
+        }
+    }
+}
+
+fn fits_huge_page(virt: VirtAddr, phys: PhysAddr, remaining: u64) -> bool {
+    virt.is_aligned(HUGE_PAGE_SIZE)
+        && phys.is_aligned(HUGE_PAGE_SIZE)
+        && remaining >= HUGE_PAGE_SIZE
+}
+
+fn huge_map_error(err: MapToError<Size2MiB>) -> MapToError<Size4KiB> {
+    match err {
+        MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+        MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+        MapToError::PageAlreadyMapped(frame) => {
+            MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address()))
+        }
+    }
+}
+
+#[test_case]
+fn test_huge_mapping() {
+    use super::{FRAME_ALLOCATOR, MAPPER};
+    use x86_64::structures::paging::OffsetPageTable;
+
+    let virt = VirtAddr::new(0x_6666_8000_0000);
+    let size = HUGE_PAGE_SIZE + Size4KiB::SIZE;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let mut mapper = MAPPER.get().unwrap().lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    let free = frame_allocator.free_frames();
+    map_anonymous(&mut *mapper, virt, size, flags, &mut *frame_allocator).unwrap();
+
+    let mapped_size = |mapper: &OffsetPageTable, addr| match mapper.translate(addr) {
+        TranslateResult::Mapped { frame, .. } => frame.size(),
+        _ => 0,
+    };
+    assert_eq!(mapped_size(&mapper, virt), HUGE_PAGE_SIZE);
+    assert_eq!(mapped_size(&mapper, virt + HUGE_PAGE_SIZE), Size4KiB::SIZE);
+    assert!(frame_allocator.free_frames() <= free - 513);
+
+    unsafe { unmap_anonymous(&mut *mapper, virt, size, &mut *frame_allocator) };
+    assert_eq!(mapped_size(&mapper, virt), 0);
+    // the page tables created for the range stay around
+    assert!(frame_allocator.free_frames() >= free - 3);
+}
@@ -0,0 +1,715 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::{
+    registers::control::Cr3,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            page_table::PageTableEntry, FrameAllocator, FrameDeallocator, Mapper, Page,
+            PageTableFlags, PhysFrame,
+        },
+    },
+    VirtAddr,
+};
+
+use super::{
+    address_space::{self, free_empty_kernel_tables, leaf_entry_in},
+    phys_to_virt, swap, BootInfoFrameAllocator, FRAME_ALLOCATOR, MAPPER,
+};
+use crate::smp::{lock::CpuMutex, tlb};
+
+/// Pages pushed out to swap at once when a fault finds no free frame.
+const RECLAIM_BATCH: usize = 16;
+
+/// Lazy regions that can exist at the same time, kernel and user ones.
+///
+/// They are kept in a fixed table instead of on the heap, because growing
+/// the heap reclaims lazy pages and so looks at the regions while holding the
+/// heap lock.
+const MAX_LAZY_REGIONS: usize = 64;
+
+/// A range of address space backed by zeroed frames on first touch.
+#[derive(Clone, Copy)]
+struct LazyRegion {
+    /// The level 4 table the region is mapped through, the kernel's unless
+    /// the region is in the user range of an address space.
+    root: PhysFrame,
+    start: VirtAddr,
+    end: VirtAddr,
+    flags: PageTableFlags,
+}
+
+/// Why a lazy region couldn't be added or changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    /// The range overlaps another region of the same address space.
+    Overlap,
+    /// All `MAX_LAZY_REGIONS` entries are in use.
+    TableFull,
+}
+
+static LAZY_REGIONS: CpuMutex<[Option<LazyRegion>; MAX_LAZY_REGIONS]> =
+    CpuMutex::new([None; MAX_LAZY_REGIONS]);
+
+/// Position among the pages of all lazy regions the next reclaim starts
+/// scanning at.
+static CLOCK_HAND: AtomicU64 = AtomicU64::new(0);
+
+/// Registers `size` bytes at `start` to be mapped on demand with `flags`.
+///
+/// Nothing is mapped up front. The page fault handler maps a zeroed frame
+/// for each page the first time it is accessed.
+pub fn register_lazy_region(start: VirtAddr, size: u64, flags: PageTableFlags) {
+    let root = address_space::kernel_root();
+    insert(root, start, size, flags).expect("failed to register lazy region");
+}
+
+/// Registers `size` bytes at `start` in the user range of the address space
+/// rooted at `root` to be mapped on demand with `flags`.
+pub(super) fn register_user_region(
+    root: PhysFrame,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), RegionError> {
+    insert(root, start, size, flags | PageTableFlags::USER_ACCESSIBLE)
+}
+
+fn insert(
+    root: PhysFrame,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), RegionError> {
+    assert!(
+        start.is_aligned(4096u64),
+        "lazy region must be page aligned"
+    );
+    let region = LazyRegion {
+        root,
+        start,
+        end: start + size,
+        flags: flags | PageTableFlags::PRESENT,
+    };
+    let mut regions = LAZY_REGIONS.lock();
+    if regions
+        .iter()
+        .flatten()
+        .any(|other| other.overlaps(&region))
+    {
+        return Err(RegionError::Overlap);
+    }
+    let slot = regions
+        .iter_mut()
+        .find(|region| region.is_none())
+        .ok_or(RegionError::TableFull)?;
+    *slot = Some(region);
+    Ok(())
+}
+
+/// Removes the region starting at `start`, unmapping and freeing every page
+/// that was faulted in or swapped out, and the page tables that held them.
+pub fn unregister_lazy_region(start: VirtAddr) {
+    let mut regions = LAZY_REGIONS.lock();
+    let root = address_space::kernel_root();
+    let region = regions
+        .iter_mut()
+        .find(
+            |region| matches!(region, Some(region) if region.root == root && region.start == start),
+        )
+        .and_then(Option::take)
+        .expect("no lazy region at this address");
+
+    let _mapper = MAPPER.get().unwrap().lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    for page in region.pages() {
+        free_page(root, page, &mut frame_allocator);
+    }
+    unsafe { free_empty_kernel_tables(region.start, region.end, &mut *frame_allocator) };
+}
+
+/// Unmaps the pages from `start` to `end` in the user range of the address
+/// space rooted at `root`, freeing their frames, and takes the range out of
+/// its lazy regions.
+///
+/// Cutting a hole into a region splits it in two, which fails if the table
+/// has no room for the second half. Nothing is unmapped in that case.
+pub(super) fn unmap_user_range(
+    root: PhysFrame,
+    start: VirtAddr,
+    end: VirtAddr,
+) -> Result<(), RegionError> {
+    if start == end {
+        return Ok(());
+    }
+    let mut regions = LAZY_REGIONS.lock();
+    let splits = regions
+        .iter()
+        .flatten()
+        .filter(|region| region.root == root && region.start < start && end < region.end)
+        .count();
+    if splits > regions.iter().filter(|region| region.is_none()).count() {
+        return Err(RegionError::TableFull);
+    }
+
+    let mut tails = [None; MAX_LAZY_REGIONS];
+    for (slot, tail) in regions.iter_mut().zip(&mut tails) {
+        let Some(region) = slot.as_mut().filter(|region| region.root == root) else {
+            continue;
+        };
+        if end <= region.start || region.end <= start {
+            continue;
+        }
+        if end < region.end {
+            *tail = Some(LazyRegion {
+                start: end,
+                ..*region
+            });
+        }
+        region.end = start;
+        if region.end <= region.start {
+            *slot = tail.take();
+        }
+    }
+    for tail in tails.into_iter().flatten() {
+        *regions.iter_mut().find(|slot| slot.is_none()).unwrap() = Some(tail);
+    }
+
+    // the regions stay locked, so nothing faults the pages back in meanwhile
+    let _mapper = MAPPER.get().unwrap().lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    let first = Page::containing_address(start);
+    for page in Page::range(first, Page::containing_address(end - 1u64) + 1) {
+        free_page(root, page, &mut frame_allocator);
+    }
+    Ok(())
+}
+
+/// Gives the address space rooted at `child` a copy of every lazy region of
+/// the one rooted at `parent`. The pages themselves aren't copied.
+pub(super) fn copy_user_regions(parent: PhysFrame, child: PhysFrame) -> Result<(), RegionError> {
+    let mut regions = LAZY_REGIONS.lock();
+    for index in 0..MAX_LAZY_REGIONS {
+        let Some(region) = regions[index].filter(|region| region.root == parent) else {
+            continue;
+        };
+        let slot = regions
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(RegionError::TableFull)?;
+        *slot = Some(LazyRegion {
+            root: child,
+            ..region
+        });
+    }
+    Ok(())
+}
+
+/// Forgets the lazy regions of the address space rooted at `root`, which is
+/// about to free its page tables.
+pub(super) fn remove_user_regions(root: PhysFrame) {
+    for slot in LAZY_REGIONS.lock().iter_mut() {
+        if slot.is_some_and(|region| region.root == root) {
+            *slot = None;
+        }
+    }
+}
+
+/// Unmaps `page` of the tables rooted at `root` and frees its frame, or its
+/// swap slot if it was swapped out.
+fn free_page(root: PhysFrame, page: Page, frame_allocator: &mut BootInfoFrameAllocator) {
+    let Some(entry) = leaf_entry_in(root, page.start_address()) else {
+        return;
+    };
+    if let Some(slot) = swap::swap_slot(entry) {
+        swap::lock().unwrap().release(slot);
+        entry.set_unused();
+    } else if entry.flags().contains(PageTableFlags::PRESENT) {
+        let frame = entry.frame().unwrap();
+        entry.set_unused();
+        tlb::flush(page.start_address());
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+}
+
+/// Evicts pages of lazy regions until at least `count` frames are free, as
+/// far as swap allows. Returns whether that many frames are free.
+///
+/// Allocations outside of lazy regions call this before taking the `MAPPER`
+/// and `FRAME_ALLOCATOR` locks, so that running out of frames pushes cold
+/// pages out to swap instead of failing.
+pub fn reserve_frames(count: usize) -> bool {
+    let Some(frame_allocator) = FRAME_ALLOCATOR.get() else {
+        return false;
+    };
+    loop {
+        let free = frame_allocator.lock().free_frames();
+        if free >= count {
+            return true;
+        }
+        if reclaim((count - free).max(RECLAIM_BATCH)) == 0 {
+            return false;
+        }
+    }
+}
+
+/// Writes up to `count` pages of lazy regions that weren't accessed recently
+/// out to swap and frees their frames. Returns how many pages were evicted.
+///
+/// Pages stay mapped while they are written, without `MAPPER` or
+/// `FRAME_ALLOCATOR` being held. A page written to in the meantime is kept.
+pub fn reclaim(count: usize) -> usize {
+    if swap::stats().is_none() {
+        return 0;
+    }
+
+    let mut evicted = 0;
+    // pages written to while being evicted are passed over, but not forever
+    for _ in 0..2 * count {
+        if evicted == count {
+            break;
+        }
+        let Some(victim) = next_victim() else {
+            break;
+        };
+        let Some(slot) = swap::lock().and_then(|mut swap| swap.store(victim.frame())) else {
+            break;
+        };
+        if swap_out(&victim, slot) {
+            evicted += 1;
+        }
+    }
+    evicted
+}
+
+/// A page picked for eviction, with its entry at the time it was picked.
+struct Victim {
+    root: PhysFrame,
+    addr: VirtAddr,
+    entry: PageTableEntry,
+}
+
+impl Victim {
+    fn frame(&self) -> PhysFrame {
+        self.entry.frame().unwrap()
+    }
+}
+
+/// Runs the clock over every page of the lazy regions, starting where the
+/// last run stopped, until it finds a page that wasn't accessed since.
+///
+/// Accessed pages get their accessed bit cleared and another chance. The
+/// page found is marked clean, so that writes to it during eviction show.
+fn next_victim() -> Option<Victim> {
+    let regions = LAZY_REGIONS.lock();
+    let _mapper = MAPPER.get().unwrap().lock();
+    let pages = regions
+        .iter()
+        .flatten()
+        .flat_map(|region| region.pages().map(move |page| (region.root, page)));
+    let total = pages.clone().count();
+    if total == 0 {
+        return None;
+    }
+    let skip = CLOCK_HAND.load(Ordering::Relaxed) as usize % total;
+
+    // two rounds, so that pages only losing their accessed bit in the first
+    // can still be picked in the second
+    let round = pages.cycle().skip(skip).take(2 * total);
+    for (position, (root, page)) in (skip + 1..).zip(round) {
+        let addr = page.start_address();
+        CLOCK_HAND.store((position % total) as u64, Ordering::Relaxed);
+
+        let Some(entry) = leaf_entry_in(root, addr) else {
+            continue;
+        };
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        if flags.contains(PageTableFlags::ACCESSED) {
+            entry.set_flags(flags - PageTableFlags::ACCESSED);
+            // other CPUs caching the page only make it look colder than it is
+            x86_64::instructions::tlb::flush(addr);
+            continue;
+        }
+
+        entry.set_flags(flags - PageTableFlags::DIRTY);
+        tlb::flush(addr);
+        return Some(Victim {
+            root,
+            addr,
+            entry: entry.clone(),
+        });
+    }
+    None
+}
+
+/// Points the entry of `victim` at the swap `slot` its contents were written
+/// to and frees its frame, unless the page was written to or unmapped since
+/// it was picked. The slot is released again in that case.
+fn swap_out(victim: &Victim, slot: u64) -> bool {
+    let regions = LAZY_REGIONS.lock();
+    let _mapper = MAPPER.get().unwrap().lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    let mut swap = swap::lock().unwrap();
+
+    let mut swapped = victim.entry.clone();
+    swap::set_swap_entry(&mut swapped, slot);
+    // the tables of a user region are freed once the region is gone
+    let live = regions
+        .iter()
+        .flatten()
+        .any(|region| region.root == victim.root && region.contains(victim.addr));
+    let replaced = live
+        && leaf_entry_in(victim.root, victim.addr).is_some_and(|entry| {
+            // the CPU sets the accessed and dirty bits without taking any lock
+            let entry = unsafe { &*(entry as *mut PageTableEntry as *const AtomicU64) };
+            let current = entry.load(Ordering::SeqCst);
+            current & !PageTableFlags::ACCESSED.bits() == raw(&victim.entry)
+                && entry
+                    .compare_exchange(current, raw(&swapped), Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+        });
+
+    if replaced {
+        tlb::flush(victim.addr);
+        unsafe { frame_allocator.deallocate_frame(victim.frame()) };
+    } else {
+        swap.release(slot);
+    }
+    replaced
+}
+
+/// Tries to resolve a page fault at `addr` by mapping a zeroed frame, or by
+/// reading the page back if it was swapped out.
+///
+/// When no frame is free, cold pages are evicted to make room. Returns
+/// `false` if the address isn't in a lazy region or the paging structures
+/// are locked by the interrupted code. Locks held by other CPUs are waited
+/// for.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    let (Some(mapper), Some(frame_allocator)) = (MAPPER.get(), FRAME_ALLOCATOR.get()) else {
+        return false;
+    };
+    // the interrupted code would never get to unlock these
+    if LAZY_REGIONS.is_locked_here()
+        || mapper.is_locked_here()
+        || frame_allocator.is_locked_here()
+        || swap::is_locked_here()
+    {
+        return false;
+    }
+
+    let mut fault = map_page(addr);
+    if let Fault::OutOfFrames = fault {
+        // the locks are free again, so cold pages can be swapped out
+        if reclaim(RECLAIM_BATCH) == 0 {
+            return false;
+        }
+        fault = map_page(addr);
+    }
+    match fault {
+        Fault::Handled(handled) => handled,
+        Fault::OutOfFrames => false,
+        Fault::SwapIn(page) => swap_in(addr, page),
+    }
+}
+
+/// What `map_page` left to do.
+enum Fault {
+    /// The fault was resolved, or can't be.
+    Handled(bool),
+    /// No frame was free.
+    OutOfFrames,
+    /// The page has to be read back from swap first.
+    SwapIn(SwappedPage),
+}
+
+/// A swapped out page, with the frame allocated to read it back into.
+struct SwappedPage {
+    root: PhysFrame,
+    entry: PageTableEntry,
+    slot: u64,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+}
+
+/// Maps a zeroed frame at `addr`, or allocates the frame to read it back
+/// into if it was swapped out.
+fn map_page(addr: VirtAddr) -> Fault {
+    let regions = LAZY_REGIONS.lock();
+    let Some(region) = find_region(&regions[..], addr) else {
+        return Fault::Handled(false);
+    };
+
+    let mut mapper = MAPPER.get().unwrap().lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+
+    let entry = leaf_entry_in(region.root, addr);
+    if entry
+        .as_deref()
+        .is_some_and(|entry| entry.flags().contains(PageTableFlags::PRESENT))
+    {
+        // mapped by another CPU faulting on the same page
+        return Fault::Handled(true);
+    }
+    let Some(frame) = frame_allocator.allocate_frame() else {
+        return Fault::OutOfFrames;
+    };
+    if let Some(entry) = entry {
+        if let Some(slot) = swap::swap_slot(entry) {
+            return Fault::SwapIn(SwappedPage {
+                root: region.root,
+                entry: entry.clone(),
+                slot,
+                frame,
+                flags: region.flags,
+            });
+        }
+    }
+
+    unsafe {
+        phys_to_virt(frame.start_address())
+            .as_mut_ptr::<u8>()
+            .write_bytes(0, 4096);
+    }
+
+    let page = Page::containing_address(addr);
+    let result = if region.root == address_space::kernel_root() {
+        unsafe { mapper.map_to(page, frame, region.flags, &mut *frame_allocator) }
+    } else {
+        unsafe {
+            address_space::map_user_page(
+                region.root,
+                page,
+                frame,
+                region.flags,
+                &mut frame_allocator,
+            )
+        }
+    };
+    match result {
+        Ok(flush) => {
+            flush.flush();
+            Fault::Handled(true)
+        }
+        Err(_) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            Fault::Handled(false)
+        }
+    }
+}
+
+/// Reads `page` back from swap without holding `MAPPER` or
+/// `FRAME_ALLOCATOR`, then maps it at `addr` unless its entry changed in the
+/// meantime.
+///
+/// A changed entry means another CPU read the page back or its region went
+/// away, and retrying the access sorts that out. The region is looked up
+/// again first, since the tables of a user region go away with it.
+fn swap_in(addr: VirtAddr, page: SwappedPage) -> bool {
+    let loaded = swap::lock().unwrap().read(page.slot, page.frame);
+
+    let regions = LAZY_REGIONS.lock();
+    let _mapper = MAPPER.get().unwrap().lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    let mut swap = swap::lock().unwrap();
+    let entry = find_region(&regions[..], addr)
+        .filter(|region| region.root == page.root)
+        .and_then(|_| leaf_entry_in(page.root, addr))
+        .filter(|entry| raw(entry) == raw(&page.entry));
+    match entry {
+        Some(entry) if loaded => {
+            entry.set_frame(page.frame, page.flags);
+            swap.release(page.slot);
+        }
+        _ => unsafe { frame_allocator.deallocate_frame(page.frame) },
+    }
+    loaded
+}
+
+/// The raw value of `entry`, every bit of which is either address or flag.
+fn raw(entry: &PageTableEntry) -> u64 {
+    entry.addr().as_u64() | entry.flags().bits()
+}
+
+/// The region containing `addr` in the active address space.
+fn find_region(regions: &[Option<LazyRegion>], addr: VirtAddr) -> Option<&LazyRegion> {
+    let active = Cr3::read().0;
+    regions.iter().flatten().find(|region| {
+        region.contains(addr)
+            && (region.root == active || region.root == address_space::kernel_root())
+    })
+}
+
+impl LazyRegion {
+    fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    fn overlaps(&self, other: &LazyRegion) -> bool {
+        self.root == other.root && self.start < other.end && other.start < self.end
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page> + Clone {
+        Page::range(
+            Page::containing_address(self.start),
+            Page::containing_address(self.end - 1u64) + 1,
+        )
+    }
+}
+
+#[test_case]
+fn test_demand_zero() {
+    use x86_64::structures::paging::Translate;
+
+    let start = VirtAddr::new(0x_6666_0000_0000);
+    // level 3 tables are never freed, so create it before counting frames
+    register_lazy_region(start, 4096, PageTableFlags::WRITABLE);
+    unsafe { start.as_ptr::<u64>().read_volatile() };
+    unregister_lazy_region(start);
+
+    let free = FRAME_ALLOCATOR.get().unwrap().lock().free_frames();
+    register_lazy_region(start, 4 * 4096, PageTableFlags::WRITABLE);
+
+    let second_page = (start + 4096u64).as_mut_ptr::<u64>();
+    unsafe {
+        assert_eq!(second_page.read_volatile(), 0);
+        second_page.write_volatile(42);
+        assert_eq!(second_page.read_volatile(), 42);
+    }
+    let mapper = MAPPER.get().unwrap().lock();
+    assert!(mapper.translate_addr(start).is_none());
+    assert!(mapper.translate_addr(start + 4096u64).is_some());
+    drop(mapper);
+
+    unregister_lazy_region(start);
+    assert!(MAPPER
+        .get()
+        .unwrap()
+        .lock()
+        .translate_addr(start + 4096u64)
+        .is_none());
+    assert_eq!(FRAME_ALLOCATOR.get().unwrap().lock().free_frames(), free);
+}
+
+/// Sets up swap space on a disk in memory, unless a test already did.
+#[cfg(test)]
+fn init_test_swap() {
+    use crate::drivers::block::{BlockDevice, BlockError, SECTOR_SIZE};
+    use alloc::{boxed::Box, vec, vec::Vec};
+
+    struct RamDisk(Vec<u8>);
+
+    impl BlockDevice for RamDisk {
+        fn sector_count(&self) -> u64 {
+            (self.0.len() / SECTOR_SIZE) as u64
+        }
+
+        fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+            let start = lba as usize * SECTOR_SIZE;
+            let data = self.0.get(start..start + buf.len());
+            buf.copy_from_slice(data.ok_or(BlockError::OutOfRange)?);
+            Ok(())
+        }
+
+        fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+            let start = lba as usize * SECTOR_SIZE;
+            let data = self.0.get_mut(start..start + buf.len());
+            data.ok_or(BlockError::OutOfRange)?.copy_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    swap::init(Box::new(RamDisk(vec![0; 16 * 4096])));
+}
+
+#[test_case]
+fn test_swap_out_and_in() {
+    use x86_64::structures::paging::Translate;
+
+    init_test_swap();
+    let start = VirtAddr::new(0x_6666_0000_0000);
+    register_lazy_region(start, 4 * 4096, PageTableFlags::WRITABLE);
+    let pages = (0..4u64).map(|i| (start + i * 4096).as_mut_ptr::<u64>());
+    for (i, page) in pages.clone().enumerate() {
+        unsafe { page.write_volatile(i as u64 + 1) };
+    }
+
+    let free = FRAME_ALLOCATOR.get().unwrap().lock().free_frames();
+    assert_eq!(reclaim(4), 4);
+    assert!(MAPPER.get().unwrap().lock().translate_addr(start).is_none());
+    assert_eq!(swap::stats().unwrap().used, 4);
+    assert_eq!(
+        FRAME_ALLOCATOR.get().unwrap().lock().free_frames(),
+        free + 4
+    );
+
+    for (i, page) in pages.enumerate() {
+        assert_eq!(unsafe { page.read_volatile() }, i as u64 + 1);
+    }
+    assert_eq!(swap::stats().unwrap().used, 0);
+    assert_eq!(FRAME_ALLOCATOR.get().unwrap().lock().free_frames(), free);
+    unregister_lazy_region(start);
+}
+
+#[test_case]
+fn test_swap_user_pages() {
+    use super::address_space::{switch_to_kernel, AddressSpace, USER_START};
+    use x86_64::structures::paging::Translate;
+
+    init_test_swap();
+    let free = FRAME_ALLOCATOR.get().unwrap().lock().free_frames();
+    let start = VirtAddr::new(USER_START);
+    let mut space = AddressSpace::new().unwrap();
+    space
+        .map_user(start, 4 * 4096, PageTableFlags::WRITABLE)
+        .unwrap();
+    let pages = (0..4u64).map(|i| (start + i * 4096).as_mut_ptr::<u64>());
+    unsafe {
+        space.activate();
+        for (i, page) in pages.clone().enumerate() {
+            page.write_volatile(i as u64 + 1);
+        }
+        switch_to_kernel();
+    }
+
+    let mapped = FRAME_ALLOCATOR.get().unwrap().lock().free_frames();
+    assert_eq!(reclaim(4), 4);
+    assert!(space.mapper().translate_addr(start).is_none());
+    assert_eq!(swap::stats().unwrap().used, 4);
+    assert_eq!(
+        FRAME_ALLOCATOR.get().unwrap().lock().free_frames(),
+        mapped + 4
+    );
+
+    unsafe {
+        space.activate();
+        for (i, page) in pages.clone().enumerate() {
+            assert_eq!(page.read_volatile(), i as u64 + 1);
+        }
+        switch_to_kernel();
+    }
+    assert_eq!(swap::stats().unwrap().used, 0);
+
+    // the child gets its own copy of pages the parent has swapped out, and
+    // dropping the parent releases their slots
+    assert_eq!(reclaim(4), 4);
+    let child = space.fork().unwrap();
+    drop(space);
+    assert_eq!(swap::stats().unwrap().used, 0);
+    unsafe {
+        child.activate();
+        for (i, page) in pages.enumerate() {
+            assert_eq!(page.read_volatile(), i as u64 + 1);
+        }
+        switch_to_kernel();
+    }
+
+    drop(child);
+    assert_eq!(FRAME_ALLOCATOR.get().unwrap().lock().free_frames(), free);
+}
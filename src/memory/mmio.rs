@@ -0,0 +1,172 @@
+use core::{mem, ptr};
+
+use generic_once_cell::Lazy;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, page::PageRange, Page, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{
+    huge::{self, HUGE_PAGE_SIZE},
+    virt::VirtRangeAllocator,
+    FRAME_ALLOCATOR, MAPPER,
+};
+
+/// Window of kernel address space that device memory gets mapped into.
+pub const MMIO_START: u64 = 0x_5555_0000_0000;
+pub const MMIO_SIZE: u64 = 1024 * 1024 * 1024;
+
+static MMIO_SPACE: Lazy<Mutex<()>, Mutex<VirtRangeAllocator>> = Lazy::new(|| {
+    Mutex::new(VirtRangeAllocator::new(
+        VirtAddr::new(MMIO_START),
+        MMIO_SIZE,
+    ))
+});
+
+/// How the CPU may cache accesses to a mapped region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    WriteBack,
+    WriteThrough,
+    Uncacheable,
+}
+
+impl CachePolicy {
+    fn flags(self) -> PageTableFlags {
+        match self {
+            CachePolicy::WriteBack => PageTableFlags::empty(),
+            CachePolicy::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CachePolicy::Uncacheable => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MmioError {
+    OutOfAddressSpace,
+    Map(MapToError<Size4KiB>),
+}
+
+/// A physical device region mapped into kernel memory by `ioremap`.
+///
+/// The mapping is removed when the region is dropped.
+pub struct MmioRegion {
+    base: VirtAddr,
+    phys: PhysAddr,
+    len: usize,
+    pages: PageRange,
+}
+
+/// Maps `len` bytes of device memory starting at `phys` with the given caching.
+pub fn ioremap(phys: PhysAddr, len: usize, cache: CachePolicy) -> Result<MmioRegion, MmioError> {
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last = PhysFrame::containing_address(phys + (len.max(1) - 1));
+    let count = last - first + 1;
+
+    // large regions like framebuffers can use huge pages if `phys` allows
+    let size = count * 4096;
+    let align = if size >= HUGE_PAGE_SIZE {
+        HUGE_PAGE_SIZE
+    } else {
+        4096
+    };
+    let start = MMIO_SPACE
+        .lock()
+        .allocate_aligned(size, align)
+        .ok_or(MmioError::OutOfAddressSpace)?;
+    let start_page = Page::containing_address(start);
+
+    // dropping the region cleans up after a partial mapping
+    let region = MmioRegion {
+        base: start + phys.as_u64() % 4096,
+        phys,
+        len,
+        pages: Page::range(start_page, start_page + count),
+    };
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | cache.flags();
+    let result = {
+        let mut mapper = MAPPER.get().unwrap().lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+        unsafe {
+            huge::map_range(
+                &mut *mapper,
+                start,
+                first.start_address(),
+                size,
+                flags,
+                &mut *frame_allocator,
+            )
+        }
+    };
+
+    result.map(|()| region).map_err(MmioError::Map)
+}
+
+impl MmioRegion {
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.base
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(
+            offset + mem::size_of::<T>() <= self.len,
+            "MMIO read out of bounds"
+        );
+        unsafe { ptr::read_volatile((self.base + offset).as_ptr()) }
+    }
+
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        assert!(
+            offset + mem::size_of::<T>() <= self.len,
+            "MMIO write out of bounds"
+        );
+        unsafe { ptr::write_volatile((self.base + offset).as_mut_ptr(), value) }
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        let start = self.pages.start.start_address();
+        let size = (self.pages.end - self.pages.start) * 4096;
+        huge::unmap_range(&mut *MAPPER.get().unwrap().lock(), start, size);
+        MMIO_SPACE.lock().deallocate(start, size);
+    }
+}
+
+#[test_case]
+fn test_ioremap_vga() {
+    use super::phys_to_virt;
+    use x86_64::structures::paging::Translate;
+
+    let region = ioremap(PhysAddr::new(0xb8f9e), 2, CachePolicy::Uncacheable).unwrap();
+    let direct: *mut u16 = phys_to_virt(PhysAddr::new(0xb8f9e)).as_mut_ptr();
+    let original = unsafe { ptr::read_volatile(direct) };
+
+    region.write::<u16>(0, 0x0e21);
+    assert_eq!(unsafe { ptr::read_volatile(direct) }, 0x0e21);
+    unsafe { ptr::write_volatile(direct, original) };
+    assert_eq!(region.read::<u16>(0), original);
+
+    let virt = region.virt_addr();
+    drop(region);
+    assert!(MAPPER.get().unwrap().lock().translate_addr(virt).is_none());
+}
@@ -0,0 +1,394 @@
+use conquer_once::spin::OnceCell;
+use x86_64::{
+    registers::control::{Cr0, Cr0Flags},
+    structures::paging::{FrameAllocator, Mapper, Page, PageTable, PhysFrame, Size4KiB, Translate},
+    PhysAddr, VirtAddr,
+};
+
+use x86_64::structures::paging::OffsetPageTable;
+
+use crate::smp::lock::CpuMutex;
+
+pub mod address_space;
+pub mod dma;
+pub mod huge;
+pub mod lazy;
+pub mod mmio;
+pub mod protection;
+pub mod regions;
+pub mod stack;
+pub mod swap;
+pub mod virt;
+
+static mut PHYSICAL_MEMORY_OFFSET: u64 = 0;
+pub static MAPPER: OnceCell<CpuMutex<OffsetPageTable<'static>>> = OnceCell::uninit();
+pub static FRAME_ALLOCATOR: OnceCell<CpuMutex<BootInfoFrameAllocator>> = OnceCell::uninit();
+
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(addr.as_u64() + unsafe { PHYSICAL_MEMORY_OFFSET })
+}
+
+pub fn virt_to_phys(addr: VirtAddr) -> Option<PhysAddr> {
+    let mapper = MAPPER.get().unwrap().lock();
+    mapper.translate_addr(addr)
+}
+
+pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET = physical_memory_offset.as_u64();
+    address_space::init();
+    // make read-only pages read-only for the kernel too, copy-on-write relies on it
+    Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    protection::enable_nx();
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    OffsetPageTable::new(level_4_table, physical_memory_offset)
+}
+
+/// Returns a mutable reference to the active level 4 table.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`. Also, this function must be only called once
+/// to avoid aliasing `&mut` references (which is undefined behavior).
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    &mut *page_table_ptr // unsafe
+}
+
+/// Creates an example mapping for the given page to frame `0xb8000`.
+pub fn create_example_mapping(
+    page: Page,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
+    let flags = Flags::PRESENT | Flags::WRITABLE;
+
+    let map_to_result = unsafe {
+        // FIXME: this is not safe, we do it only for testing
+        mapper.map_to(page, frame, flags, frame_allocator)
+    };
+    map_to_result.expect("map_to failed").flush();
+}
+
+pub struct EmptyFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        None
+    }
+}
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::structures::paging::{frame::PhysFrameRange, FrameDeallocator, PageSize, Size2MiB};
+
+const FRAME_SIZE: usize = 4096;
+
+/// A bitmap-based FrameAllocator built from the bootloader's memory map.
+///
+/// Every physical frame up to the end of the last usable region gets one bit,
+/// set while the frame is in use, and a reference count so frames can be
+/// shared between mappings. Both arrays are stored in the first usable region
+/// big enough to hold them and accessed through the physical memory mapping,
+/// so they need no heap.
+pub struct BootInfoFrameAllocator {
+    bitmap: &'static mut [u64],
+    ref_counts: &'static mut [u16],
+    usable: usize,
+    used: usize,
+    next: usize,
+}
+
+impl BootInfoFrameAllocator {
+    /// Builds the allocator from the passed memory map.
+    ///
+    /// This function is unsafe because the caller must guarantee that the
+    /// memory map is valid and that `init` was called first, so that physical
+    /// memory is reachable through `phys_to_virt`.
+    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        let frame_count = usable_regions()
+            .map(|r| r.range.end_frame_number as usize)
+            .max()
+            .unwrap_or(0);
+        let words = (frame_count + 63) / 64;
+        let meta_size = words * 8 + frame_count * 2;
+        let meta_frames = (meta_size + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        let meta_start = usable_regions()
+            .find(|r| {
+                (r.range.end_frame_number - r.range.start_frame_number) as usize >= meta_frames
+            })
+            .expect("no usable region large enough for the frame bitmap")
+            .range
+            .start_frame_number as usize;
+
+        let meta_addr = phys_to_virt(PhysAddr::new((meta_start * FRAME_SIZE) as u64));
+        let bitmap = core::slice::from_raw_parts_mut(meta_addr.as_mut_ptr::<u64>(), words);
+        // everything outside of the usable regions stays marked as used
+        bitmap.fill(u64::MAX);
+        let ref_counts_addr = meta_addr + words * 8;
+        let ref_counts =
+            core::slice::from_raw_parts_mut(ref_counts_addr.as_mut_ptr::<u16>(), frame_count);
+        ref_counts.fill(0);
+
+        let mut allocator = BootInfoFrameAllocator {
+            bitmap,
+            ref_counts,
+            usable: 0,
+            used: 0,
+            next: 0,
+        };
+
+        for region in usable_regions() {
+            let start = region.range.start_frame_number as usize;
+            let end = region.range.end_frame_number as usize;
+            for frame in start..end {
+                allocator.mark_free(frame);
+            }
+            allocator.usable += end - start;
+        }
+
+        for frame in meta_start..meta_start + meta_frames {
+            allocator.mark_used(frame);
+            allocator.ref_counts[frame] = 1;
+        }
+        allocator.used = meta_frames;
+
+        allocator
+    }
+
+    /// Number of usable frames managed by the allocator.
+    pub fn total_frames(&self) -> usize {
+        self.usable
+    }
+
+    pub fn used_frames(&self) -> usize {
+        self.used
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.usable - self.used
+    }
+
+    /// Allocates `count` physically contiguous frames.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrameRange> {
+        self.allocate_contiguous_constrained(count, 1, u64::MAX)
+    }
+
+    /// Allocates `count` physically contiguous frames, starting at a multiple
+    /// of `align` bytes and lying entirely at or below `max_addr`.
+    pub fn allocate_contiguous_constrained(
+        &mut self,
+        count: usize,
+        align: u64,
+        max_addr: u64,
+    ) -> Option<PhysFrameRange> {
+        let align = (align as usize / FRAME_SIZE).max(1);
+        let end =
+            max_addr as usize / FRAME_SIZE + (max_addr as usize % FRAME_SIZE + 1) / FRAME_SIZE;
+        let start = self.find_free_run(count, align, end)?;
+        for frame in start..start + count {
+            self.mark_used(frame);
+            self.ref_counts[frame] = 1;
+        }
+        self.used += count;
+        Some(PhysFrame::range(frame_at(start), frame_at(start + count)))
+    }
+
+    /// Allocates a 2 MiB aligned run of frames to back a huge page.
+    pub fn allocate_huge_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let range = self.allocate_contiguous_constrained(512, Size2MiB::SIZE, u64::MAX)?;
+        Some(PhysFrame::containing_address(range.start.start_address()))
+    }
+
+    /// Adds a reference to an allocated frame, so that it is only freed once
+    /// `deallocate_frame` has been called for every reference.
+    ///
+    /// Returns `false` if the frame already has as many references as can be
+    /// counted.
+    pub fn share_frame(&mut self, frame: PhysFrame) -> bool {
+        let index = frame_index(frame);
+        assert!(self.is_used(index), "sharing free {frame:?}");
+        match self.ref_counts[index].checked_add(1) {
+            Some(count) => {
+                self.ref_counts[index] = count;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Number of references to `frame`, zero if it's free or not managed.
+    pub fn ref_count(&self, frame: PhysFrame) -> u16 {
+        self.ref_counts
+            .get(frame_index(frame))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Returns a range obtained from `allocate_contiguous`.
+    ///
+    /// This function is unsafe because the caller must guarantee that none of
+    /// the frames are still in use.
+    pub unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        for frame in range {
+            self.deallocate_frame(frame);
+        }
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    fn mark_used(&mut self, frame: usize) {
+        self.bitmap[frame / 64] |= 1 << (frame % 64);
+    }
+
+    fn mark_free(&mut self, frame: usize) {
+        self.bitmap[frame / 64] &= !(1 << (frame % 64));
+    }
+
+    fn find_free_frame(&self) -> Option<usize> {
+        // skip full words, starting at the hint and wrapping around
+        let words = self.bitmap.len();
+        let start = self.next / 64;
+        (start..words).chain(0..start).find_map(|i| {
+            let word = self.bitmap[i];
+            (word != u64::MAX).then(|| i * 64 + (!word).trailing_zeros() as usize)
+        })
+    }
+
+    /// Finds `count` free frames starting at a multiple of `align` frames and
+    /// ending before frame `end`.
+    fn find_free_run(&self, count: usize, align: usize, end: usize) -> Option<usize> {
+        if count == 0 {
+            return None;
+        }
+
+        let end = end.min(self.bitmap.len() * 64);
+        let mut run_start = 0;
+        let mut frame = 0;
+        while frame < end {
+            if self.is_used(frame) {
+                run_start = (frame + align) / align * align;
+                frame = run_start;
+            } else if frame + 1 - run_start == count {
+                return Some(run_start);
+            } else {
+                frame += 1;
+            }
+        }
+        None
+    }
+}
+
+fn frame_at(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new((index * FRAME_SIZE) as u64))
+}
+
+fn frame_index(frame: PhysFrame) -> usize {
+    frame.start_address().as_u64() as usize / FRAME_SIZE
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.find_free_frame()?;
+        self.mark_used(frame);
+        self.ref_counts[frame] = 1;
+        self.used += 1;
+        self.next = frame + 1;
+        Some(frame_at(frame))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = frame_index(frame);
+        assert!(self.is_used(index), "double free of {frame:?}");
+        // reserved frames, such as the bootloader's, are used but not counted
+        assert!(self.ref_counts[index] > 0, "freeing reserved {frame:?}");
+        self.ref_counts[index] -= 1;
+        if self.ref_counts[index] == 0 {
+            self.mark_free(index);
+            self.used -= 1;
+            self.next = self.next.min(index);
+        }
+    }
+}
+
+#[test_case]
+fn test_frame_reuse() {
+    let mut allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    let used = allocator.used_frames();
+    let frame = allocator.allocate_frame().unwrap();
+    assert_eq!(allocator.used_frames(), used + 1);
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.used_frames(), used);
+    assert_eq!(allocator.allocate_frame(), Some(frame));
+    unsafe { allocator.deallocate_frame(frame) };
+}
+
+#[test_case]
+fn test_shared_frame() {
+    let mut allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    let used = allocator.used_frames();
+    let frame = allocator.allocate_frame().unwrap();
+    assert!(allocator.share_frame(frame));
+    assert_eq!(allocator.ref_count(frame), 2);
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.used_frames(), used + 1);
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.ref_count(frame), 0);
+    assert_eq!(allocator.used_frames(), used);
+}
+
+#[test_case]
+fn test_share_frame_limit() {
+    let mut allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    let frame = allocator.allocate_frame().unwrap();
+    while allocator.ref_count(frame) < u16::MAX {
+        assert!(allocator.share_frame(frame));
+    }
+    assert!(!allocator.share_frame(frame));
+    for _ in 0..u16::MAX {
+        unsafe { allocator.deallocate_frame(frame) };
+    }
+    assert_eq!(allocator.ref_count(frame), 0);
+}
+
+#[test_case]
+fn test_contiguous_allocation() {
+    let mut allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    let free = allocator.free_frames();
+    let range = allocator.allocate_contiguous(16).unwrap();
+    assert_eq!(range.end - range.start, 16);
+    assert_eq!(allocator.free_frames(), free - 16);
+    unsafe { allocator.deallocate_contiguous(range) };
+    assert_eq!(allocator.free_frames(), free);
+}
+
+#[test_case]
+fn test_huge_frame_alignment() {
+    let mut allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    let free = allocator.free_frames();
+    let frame = allocator.allocate_huge_frame().unwrap();
+    assert!(frame.start_address().is_aligned(Size2MiB::SIZE));
+    assert_eq!(allocator.free_frames(), free - 512);
+    let start = PhysFrame::containing_address(frame.start_address());
+    unsafe { allocator.deallocate_contiguous(PhysFrame::range(start, start + 512)) };
+    assert_eq!(allocator.free_frames(), free);
+}
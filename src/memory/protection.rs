@@ -0,0 +1,173 @@
+use core::{arch::asm, ptr::addr_of};
+
+use bootloader::bootinfo::MemoryRegionType;
+use x86_64::{
+    instructions::tlb,
+    registers::model_specific::{Efer, EferFlags},
+    structures::paging::{
+        mapper::{MappedFrame, TranslateResult},
+        Mapper, OffsetPageTable, Page, PageTableFlags, Size1GiB, Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{phys_to_virt, regions};
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+extern "C" {
+    /// The kernel's ELF header, which the linker maps in front of the first
+    /// loadable segment.
+    static __ehdr_start: u8;
+}
+
+/// An ELF64 program header.
+#[allow(dead_code)]
+#[repr(C)]
+struct ProgramHeader {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: u64,
+    p_vaddr: u64,
+    p_paddr: u64,
+    p_filesz: u64,
+    p_memsz: u64,
+    p_align: u64,
+}
+
+/// Makes the CPU honour `NO_EXECUTE` in page table entries.
+///
+/// Until this has run, setting `NO_EXECUTE` is a reserved bit violation, so
+/// it has to happen before anything maps non-executable pages.
+pub(super) unsafe fn enable_nx() {
+    Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+}
+
+/// Remaps the kernel image so that no page is both writable and executable,
+/// and marks the physical memory mapping and the boot stack non-executable.
+///
+/// Text ends up read-only, rodata read-only and non-executable, and data
+/// writable but non-executable, following the kernel's program headers.
+pub fn init(mapper: &mut OffsetPageTable) {
+    for header in program_headers().filter(|h| h.p_type == PT_LOAD) {
+        let start = VirtAddr::new(header.p_vaddr);
+        let end = start + header.p_memsz;
+        let writable = header.p_flags & PF_W != 0;
+        let executable = header.p_flags & PF_X != 0 && !writable;
+        update_flags(mapper, start, end, |mut flags| {
+            flags.set(PageTableFlags::WRITABLE, writable);
+            flags.set(PageTableFlags::NO_EXECUTE, !executable);
+            flags
+        });
+    }
+
+    // covers every DMA buffer, page table and frame the kernel touches directly
+    let phys_end = regions::regions()
+        .map(|region| region.end.as_u64())
+        .max()
+        .unwrap_or(0);
+    let start = phys_to_virt(PhysAddr::new(0));
+    update_flags(mapper, start, start + phys_end, |flags| {
+        flags | PageTableFlags::NO_EXECUTE
+    });
+
+    // the bootloader maps its stack executable, and the kernel keeps running
+    // on it
+    let (start, end) = boot_stack(mapper);
+    update_flags(mapper, start, end, |flags| {
+        flags | PageTableFlags::NO_EXECUTE
+    });
+
+    tlb::flush_all();
+}
+
+/// The virtual range of the stack the bootloader set up, found by walking
+/// from the stack pointer over the pages backed by its `KernelStack` region.
+fn boot_stack(mapper: &OffsetPageTable) -> (VirtAddr, VirtAddr) {
+    let on_stack = |addr: VirtAddr| {
+        mapper.translate_addr(addr).is_some_and(|phys| {
+            regions::regions().any(|region| {
+                region.kind == MemoryRegionType::KernelStack
+                    && (region.start..region.end).contains(&phys)
+            })
+        })
+    };
+
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    let mut start = VirtAddr::new(rsp).align_down(4096u64);
+    let mut end = start + 4096u64;
+    while on_stack(start - 4096u64) {
+        start -= 4096u64;
+    }
+    while on_stack(end) {
+        end += 4096u64;
+    }
+    (start, end)
+}
+
+fn program_headers() -> impl Iterator<Item = ProgramHeader> {
+    let ehdr = unsafe { addr_of!(__ehdr_start) };
+    let (phoff, phentsize, phnum) = unsafe {
+        (
+            ehdr.add(32).cast::<u64>().read_unaligned() as usize,
+            ehdr.add(54).cast::<u16>().read_unaligned() as usize,
+            ehdr.add(56).cast::<u16>().read_unaligned() as usize,
+        )
+    };
+    (0..phnum).map(move |i| unsafe {
+        ehdr.add(phoff + i * phentsize)
+            .cast::<ProgramHeader>()
+            .read_unaligned()
+    })
+}
+
+/// Replaces the flags of every page mapped between `start` and `end` with
+/// `f(flags)`, whichever page size it uses.
+///
+/// The caller is responsible for flushing the TLB.
+fn update_flags(
+    mapper: &mut OffsetPageTable,
+    start: VirtAddr,
+    end: VirtAddr,
+    f: impl Fn(PageTableFlags) -> PageTableFlags,
+) {
+    let mut addr = start.align_down(4096u64);
+    while addr < end {
+        let TranslateResult::Mapped { frame, flags, .. } = mapper.translate(addr) else {
+            addr += 4096u64;
+            continue;
+        };
+        let new_flags = f(flags);
+        let page_start = addr.align_down(frame.size());
+        if new_flags != flags {
+            let result = unsafe {
+                match frame {
+                    MappedFrame::Size4KiB(_) => mapper
+                        .update_flags(Page::<Size4KiB>::containing_address(page_start), new_flags)
+                        .map(|flush| flush.ignore()),
+                    MappedFrame::Size2MiB(_) => mapper
+                        .update_flags(Page::<Size2MiB>::containing_address(page_start), new_flags)
+                        .map(|flush| flush.ignore()),
+                    MappedFrame::Size1GiB(_) => mapper
+                        .update_flags(Page::<Size1GiB>::containing_address(page_start), new_flags)
+                        .map(|flush| flush.ignore()),
+                }
+            };
+            result.expect("failed to update kernel page flags");
+        }
+        addr = page_start + frame.size();
+    }
+}
+
+#[test_case]
+fn test_boot_stack_not_executable() {
+    let mapper = super::MAPPER.get().unwrap().lock();
+    let local = 0u8;
+    let TranslateResult::Mapped { flags, .. } = mapper.translate(VirtAddr::from_ptr(&local)) else {
+        panic!("stack not mapped");
+    };
+    assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+}
@@ -0,0 +1,67 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use conquer_once::spin::OnceCell;
+use x86_64::PhysAddr;
+
+static MEMORY_MAP: OnceCell<&'static MemoryMap> = OnceCell::uninit();
+
+/// Keeps the bootloader's memory map around for `regions` and `totals`.
+pub fn init(memory_map: &'static MemoryMap) {
+    MEMORY_MAP.init_once(|| memory_map);
+}
+
+/// A physical memory region as reported by the bootloader.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub kind: MemoryRegionType,
+    pub start: PhysAddr,
+    /// First address past the region.
+    pub end: PhysAddr,
+}
+
+impl Region {
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+/// Bytes of physical memory by what the bootloader reported them as.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryTotals {
+    /// Memory handed to the frame allocator.
+    pub usable: u64,
+    /// Everything else, including the kernel image and bootloader data.
+    pub reserved: u64,
+}
+
+/// Iterates over every region of the memory map, in address order.
+pub fn regions() -> impl Iterator<Item = Region> {
+    MEMORY_MAP
+        .get()
+        .into_iter()
+        .flat_map(|map| map.iter())
+        .map(|region| Region {
+            kind: region.region_type,
+            start: PhysAddr::new(region.range.start_addr()),
+            end: PhysAddr::new(region.range.end_addr()),
+        })
+}
+
+pub fn totals() -> MemoryTotals {
+    regions().fold(MemoryTotals::default(), |mut totals, region| {
+        if region.kind == MemoryRegionType::Usable {
+            totals.usable += region.size();
+        } else {
+            totals.reserved += region.size();
+        }
+        totals
+    })
+}
+
+#[test_case]
+fn test_usable_totals() {
+    use super::FRAME_ALLOCATOR;
+
+    assert!(regions().any(|r| r.kind == MemoryRegionType::Kernel));
+    let usable = FRAME_ALLOCATOR.get().unwrap().lock().total_frames() as u64 * 4096;
+    assert_eq!(totals().usable, usable);
+}
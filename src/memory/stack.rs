@@ -0,0 +1,154 @@
+use core::sync::atomic::{AtomicU16, Ordering};
+use generic_once_cell::Lazy;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, page::PageRange, FrameAllocator, FrameDeallocator, Mapper, Page,
+        PageTableFlags,
+    },
+    VirtAddr,
+};
+
+use super::{lazy, virt::VirtRangeAllocator, FRAME_ALLOCATOR, MAPPER};
+use crate::smp::tlb;
+
+/// Window of kernel address space that stacks are allocated from.
+pub const STACK_REGION_START: u64 = 0x_7777_0000_0000;
+pub const STACK_REGION_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Every stack gets a slot of this size. Only the top of the slot is mapped,
+/// everything below the stack stays unmapped and acts as its guard.
+const STACK_SLOT_SIZE: u64 = 256 * 1024;
+const STACK_SLOTS: usize = (STACK_REGION_SIZE / STACK_SLOT_SIZE) as usize;
+
+/// Pages of the live stack in each slot, zero for free slots.
+///
+/// Kept outside of `STACK_SPACE` so that fault handlers can read it without
+/// taking locks.
+static STACK_PAGES: [AtomicU16; STACK_SLOTS] = [const { AtomicU16::new(0) }; STACK_SLOTS];
+
+static STACK_SPACE: Lazy<Mutex<()>, Mutex<VirtRangeAllocator>> = Lazy::new(|| {
+    Mutex::new(VirtRangeAllocator::new(
+        VirtAddr::new(STACK_REGION_START),
+        STACK_REGION_SIZE,
+    ))
+});
+
+/// A kernel stack with unmapped guard pages below it.
+///
+/// The memory is unmapped and freed again when the stack is dropped.
+pub struct KernelStack {
+    slot: VirtAddr,
+    pages: PageRange,
+}
+
+impl KernelStack {
+    /// Maps a stack of at least `size` bytes.
+    ///
+    /// Returns `None` if the stack wouldn't leave room for a guard page or if
+    /// there is no memory left.
+    pub fn new(size: usize) -> Option<Self> {
+        let count = (size as u64 + 4095) / 4096;
+        if count == 0 || count * 4096 >= STACK_SLOT_SIZE {
+            return None;
+        }
+
+        let slot = STACK_SPACE
+            .lock()
+            .allocate_aligned(STACK_SLOT_SIZE, STACK_SLOT_SIZE)?;
+        let top = Page::containing_address(slot + STACK_SLOT_SIZE);
+        // dropping the stack cleans up after a partial mapping
+        let stack = Self {
+            slot,
+            pages: Page::range(top - count, top),
+        };
+        STACK_PAGES[slot_index(slot)].store(count as u16, Ordering::SeqCst);
+
+        lazy::reserve_frames(count as usize);
+        let result = {
+            let mut mapper = MAPPER.get().unwrap().lock();
+            let mut frame_allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+            let flags =
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            let mut pages = stack.pages;
+            pages.try_for_each(|page| {
+                let frame = frame_allocator
+                    .allocate_frame()
+                    .ok_or(MapToError::FrameAllocationFailed)?;
+                unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator) }
+                    .map(|flush| flush.flush())
+            })
+        };
+
+        result.ok().map(|()| stack)
+    }
+
+    /// The initial stack pointer, one past the highest usable byte.
+    pub fn top(&self) -> VirtAddr {
+        self.pages.end.start_address()
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.pages.start.start_address()
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        STACK_PAGES[slot_index(self.slot)].store(0, Ordering::SeqCst);
+        {
+            let mut mapper = MAPPER.get().unwrap().lock();
+            let mut frame_allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+            for page in self.pages {
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    flush.ignore();
+                    tlb::flush(page.start_address());
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+            }
+        }
+
+        STACK_SPACE.lock().deallocate(self.slot, STACK_SLOT_SIZE);
+    }
+}
+
+fn slot_index(slot: VirtAddr) -> usize {
+    ((slot.as_u64() - STACK_REGION_START) / STACK_SLOT_SIZE) as usize
+}
+
+/// Whether a fault at `addr` hit the guard page right below a live kernel
+/// stack, meaning the stack overflowed.
+///
+/// Faults anywhere else in the stack region, such as on a dropped stack,
+/// don't count. This takes no locks and is safe to call from the double
+/// fault handler.
+pub fn is_stack_guard(addr: VirtAddr) -> bool {
+    let offset = addr.as_u64().wrapping_sub(STACK_REGION_START);
+    if offset >= STACK_REGION_SIZE {
+        return false;
+    }
+    let slot = (offset / STACK_SLOT_SIZE) as usize;
+    let pages = STACK_PAGES[slot].load(Ordering::SeqCst) as u64;
+    let bottom = (slot as u64 + 1) * STACK_SLOT_SIZE - pages * 4096;
+    pages != 0 && (bottom - 4096..bottom).contains(&offset)
+}
+
+#[test_case]
+fn test_stack_guard() {
+    use x86_64::structures::paging::Translate;
+
+    let stack = KernelStack::new(4 * 4096).unwrap();
+    assert_eq!(stack.top() - stack.bottom(), 4 * 4096);
+
+    let mapper = MAPPER.get().unwrap().lock();
+    assert!(mapper.translate_addr(stack.top() - 1u64).is_some());
+    assert!(mapper.translate_addr(stack.bottom()).is_some());
+    assert!(mapper.translate_addr(stack.bottom() - 1u64).is_none());
+    assert!(is_stack_guard(stack.bottom() - 1u64));
+    assert!(!is_stack_guard(stack.bottom() - 4097u64));
+    drop(mapper);
+
+    let bottom = stack.bottom();
+    drop(stack);
+    assert!(!is_stack_guard(bottom - 1u64));
+}
@@ -0,0 +1,125 @@
+use alloc::vec::Vec;
+use alloc::{boxed::Box, vec};
+use conquer_once::spin::OnceCell;
+use x86_64::{
+    structures::paging::{page_table::PageTableEntry, PageTableFlags, PhysFrame},
+    PhysAddr,
+};
+
+use super::phys_to_virt;
+use crate::{
+    drivers::block::{BlockDevice, SECTOR_SIZE},
+    smp::lock::{CpuMutex, CpuMutexGuard},
+};
+
+/// Marks a non-present entry whose page was written out. The slot number is
+/// kept in the entry's address bits.
+const SWAPPED: PageTableFlags = PageTableFlags::BIT_10;
+
+const SECTORS_PER_SLOT: u64 = (4096 / SECTOR_SIZE) as u64;
+
+static SWAP: OnceCell<CpuMutex<Swap>> = OnceCell::uninit();
+
+/// Page sized slots on a block device that evicted pages are written to.
+pub struct Swap {
+    device: Box<dyn BlockDevice + Send>,
+    bitmap: Vec<u64>,
+    slots: usize,
+    used: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SwapStats {
+    pub slots: usize,
+    pub used: usize,
+}
+
+/// Uses all of `device` as swap space, overwriting whatever is on it.
+pub fn init(device: Box<dyn BlockDevice + Send>) {
+    let slots = (device.sector_count() / SECTORS_PER_SLOT) as usize;
+    let bitmap = vec![0; (slots + 63) / 64];
+    SWAP.init_once(|| {
+        CpuMutex::new(Swap {
+            device,
+            bitmap,
+            slots,
+            used: 0,
+        })
+    });
+}
+
+pub fn stats() -> Option<SwapStats> {
+    let swap = SWAP.get()?.lock();
+    Some(SwapStats {
+        slots: swap.slots,
+        used: swap.used,
+    })
+}
+
+/// Locks the swap space, if there is one.
+pub(super) fn lock() -> Option<CpuMutexGuard<'static, Swap>> {
+    Some(SWAP.get()?.lock())
+}
+
+/// Whether the calling CPU holds the swap space lock.
+pub(super) fn is_locked_here() -> bool {
+    SWAP.get().is_some_and(CpuMutex::is_locked_here)
+}
+
+impl Swap {
+    /// Writes `frame` to a free slot and returns the slot.
+    pub(super) fn store(&mut self, frame: PhysFrame) -> Option<u64> {
+        let slot = (0..self.slots).find(|&slot| !self.is_used(slot))?;
+        let data = unsafe { frame_bytes(frame) };
+        self.device
+            .write_sectors(slot as u64 * SECTORS_PER_SLOT, data)
+            .ok()?;
+        self.bitmap[slot / 64] |= 1 << (slot % 64);
+        self.used += 1;
+        Some(slot as u64)
+    }
+
+    /// Reads `slot` back into `frame`, keeping the slot.
+    pub(super) fn read(&mut self, slot: u64, frame: PhysFrame) -> bool {
+        let data = unsafe { frame_bytes(frame) };
+        self.device
+            .read_sectors(slot * SECTORS_PER_SLOT, data)
+            .is_ok()
+    }
+
+    /// Frees `slot` without reading it.
+    pub(super) fn release(&mut self, slot: u64) {
+        let slot = slot as usize;
+        assert!(self.is_used(slot), "releasing free swap slot {slot}");
+        self.bitmap[slot / 64] &= !(1 << (slot % 64));
+        self.used -= 1;
+    }
+
+    fn is_used(&self, slot: usize) -> bool {
+        self.bitmap[slot / 64] & (1 << (slot % 64)) != 0
+    }
+}
+
+unsafe fn frame_bytes(frame: PhysFrame) -> &'static mut [u8] {
+    let addr = phys_to_virt(frame.start_address());
+    core::slice::from_raw_parts_mut(addr.as_mut_ptr(), 4096)
+}
+
+/// Turns `entry` into a non-present entry pointing at `slot`, keeping its
+/// flags for when the page comes back.
+pub(super) fn set_swap_entry(entry: &mut PageTableEntry, slot: u64) {
+    let flags = (entry.flags() - PageTableFlags::PRESENT) | SWAPPED;
+    entry.set_addr(PhysAddr::new(slot << 12), flags);
+}
+
+/// Returns the flags a swapped out `entry` had while it was present.
+pub(super) fn swapped_flags(entry: &PageTableEntry) -> PageTableFlags {
+    (entry.flags() - SWAPPED) | PageTableFlags::PRESENT
+}
+
+/// Returns the slot a non-present entry was swapped out to.
+pub(super) fn swap_slot(entry: &PageTableEntry) -> Option<u64> {
+    let flags = entry.flags();
+    (flags.contains(SWAPPED) && !flags.contains(PageTableFlags::PRESENT))
+        .then(|| entry.addr().as_u64() >> 12)
+}
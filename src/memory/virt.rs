@@ -0,0 +1,64 @@
+use core::ops::Range;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use x86_64::{align_up, VirtAddr};
+
+/// Hands out page-aligned ranges from a fixed window of kernel address space.
+///
+/// Free ranges are kept sorted by address and merged again when returned.
+pub struct VirtRangeAllocator {
+    free: Vec<Range<u64>>,
+}
+
+impl VirtRangeAllocator {
+    pub fn new(start: VirtAddr, size: u64) -> Self {
+        let start = start.as_u64();
+        Self {
+            free: vec![start..start + size],
+        }
+    }
+
+    /// Reserves `size` bytes, which must be a multiple of the page size.
+    pub fn allocate(&mut self, size: u64) -> Option<VirtAddr> {
+        self.allocate_aligned(size, 1)
+    }
+
+    /// Reserves `size` bytes starting at a multiple of `align`, which must be
+    /// a power of two.
+    pub fn allocate_aligned(&mut self, size: u64, align: u64) -> Option<VirtAddr> {
+        let (index, start) = self.free.iter().enumerate().find_map(|(i, r)| {
+            let start = align_up(r.start, align);
+            (start + size <= r.end).then_some((i, start))
+        })?;
+
+        // keep whatever is left on either side of the reservation
+        let range = self.free.remove(index);
+        if start + size < range.end {
+            self.free.insert(index, start + size..range.end);
+        }
+        if range.start < start {
+            self.free.insert(index, range.start..start);
+        }
+        Some(VirtAddr::new(start))
+    }
+
+    /// Returns a range previously handed out by `allocate`.
+    pub fn deallocate(&mut self, start: VirtAddr, size: u64) {
+        let start = start.as_u64();
+        let end = start + size;
+        let index = self.free.partition_point(|r| r.end <= start);
+
+        let joins_prev = index > 0 && self.free[index - 1].end == start;
+        let joins_next = index < self.free.len() && self.free[index].start == end;
+        match (joins_prev, joins_next) {
+            (true, true) => {
+                self.free[index - 1].end = self.free[index].end;
+                self.free.remove(index);
+            }
+            (true, false) => self.free[index - 1].end = end,
+            (false, true) => self.free[index].start = start,
+            (false, false) => self.free.insert(index, start..end),
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use alloc::{boxed::Box, sync::Arc, vec::Vec};
 use smoltcp::{
     iface::{Config, Interface},
@@ -19,6 +21,12 @@ pub trait EthernetDevice: Send + 'static {
     fn get_capabilities(&self) -> DeviceCapabilities;
     fn mac(&self) -> HardwareAddress;
     fn transmit_packet(&mut self, len: usize);
+    /// Takes the next received packet off the device.
+    ///
+    /// Returns `None` when nothing is waiting. A packet that can't be copied
+    /// out because the heap is exhausted is dropped rather than aborting the
+    /// kernel, which also returns `None`; drops are only told apart by
+    /// `dropped_packets`, which `meminfo` shows.
     fn receive_packet(&mut self) -> Option<Vec<u8>>;
     fn get_transmit_buffer(&mut self, len: usize) -> &mut [u8];
 }
@@ -73,6 +81,25 @@ impl phy::Device for dyn EthernetDevice {
     }
 }
 
+static DROPPED_PACKETS: AtomicUsize = AtomicUsize::new(0);
+
+/// Copies a received packet out of a driver's buffer, dropping it if there's
+/// no memory left for it.
+pub fn copy_packet(data: &[u8]) -> Option<Vec<u8>> {
+    let mut packet = Vec::new();
+    if packet.try_reserve_exact(data.len()).is_err() {
+        DROPPED_PACKETS.fetch_add(1, Ordering::Relaxed);
+        return None;
+    }
+    packet.extend_from_slice(data);
+    Some(packet)
+}
+
+/// Number of received packets dropped for lack of memory.
+pub fn dropped_packets() -> usize {
+    DROPPED_PACKETS.load(Ordering::Relaxed)
+}
+
 pub static NET_IFACES: Mutex<Vec<Arc<Mutex<NetworkInterfaceInner>>>> = Mutex::new(Vec::new());
 
 pub fn add_interface(device: PciDevice) -> Option<NetworkInterface> {
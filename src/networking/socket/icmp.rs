@@ -1,10 +1,11 @@
-use alloc::vec;
+use alloc::collections::TryReserveError;
 use alloc::vec::Vec;
 use smoltcp::iface::SocketHandle;
 use smoltcp::phy::DeviceCapabilities;
 use smoltcp::socket::icmp::*;
 use smoltcp::wire::{Icmpv4Packet, Icmpv4Repr, IpAddress};
 
+use crate::allocator::try_vec;
 use crate::networking::wait_for_socket_state_change;
 use crate::task::network::notify_tx;
 
@@ -16,11 +17,17 @@ pub struct IcmpSocket {
 
 impl IcmpSocket {
     pub fn new() -> Self {
-        let rx_buffer = PacketBuffer::new(vec![PacketMetadata::EMPTY], vec![0; 256]);
-        let tx_buffer = PacketBuffer::new(vec![PacketMetadata::EMPTY], vec![0; 256]);
+        Self::try_new().expect("out of memory for ICMP socket buffers")
+    }
+
+    /// Like `new`, but fails instead of aborting if the buffers don't fit on
+    /// the heap.
+    pub fn try_new() -> Result<Self, TryReserveError> {
+        let rx_buffer = PacketBuffer::new(try_vec(PacketMetadata::EMPTY, 1)?, try_vec(0, 256)?);
+        let tx_buffer = PacketBuffer::new(try_vec(PacketMetadata::EMPTY, 1)?, try_vec(0, 256)?);
         let inner = Socket::new(rx_buffer, tx_buffer);
         let handle = SOCKETS.get().unwrap().lock().add(inner);
-        Self { handle }
+        Ok(Self { handle })
     }
 
     pub fn with_inner<R>(&mut self, f: impl FnOnce(&mut Socket) -> R) -> R {
@@ -1,4 +1,4 @@
-use alloc::vec;
+use alloc::collections::TryReserveError;
 use smoltcp::{
     iface::SocketHandle,
     socket::tcp::{self, Socket},
@@ -6,6 +6,7 @@ use smoltcp::{
 };
 
 use crate::{
+    allocator::try_vec,
     networking::{wait_for_socket_state_change, NetworkInterface},
     println,
     task::network::notify_tx,
@@ -19,11 +20,17 @@ pub struct TcpStream {
 
 impl TcpStream {
     pub fn new() -> Self {
-        let rx_buffer = tcp::SocketBuffer::new(vec![0; 1024]);
-        let tx_buffer = tcp::SocketBuffer::new(vec![0; 1024]);
+        Self::try_new().expect("out of memory for TCP socket buffers")
+    }
+
+    /// Like `new`, but fails instead of aborting if the buffers don't fit on
+    /// the heap.
+    pub fn try_new() -> Result<Self, TryReserveError> {
+        let rx_buffer = tcp::SocketBuffer::new(try_vec(0, 1024)?);
+        let tx_buffer = tcp::SocketBuffer::new(try_vec(0, 1024)?);
         let inner = tcp::Socket::new(rx_buffer, tx_buffer);
         let handle = SOCKETS.get().unwrap().lock().add(inner);
-        Self { handle }
+        Ok(Self { handle })
     }
 
     pub fn with_inner<R>(&mut self, f: impl FnOnce(&mut Socket) -> R) -> R {
@@ -84,6 +91,26 @@ impl TcpStream {
     }
 }
 
+/// Why a listening socket couldn't be set up.
+#[derive(Debug)]
+pub enum ListenError {
+    Listen(tcp::ListenError),
+    /// The socket buffers didn't fit on the heap.
+    OutOfMemory(TryReserveError),
+}
+
+impl From<tcp::ListenError> for ListenError {
+    fn from(error: tcp::ListenError) -> Self {
+        ListenError::Listen(error)
+    }
+}
+
+impl From<TryReserveError> for ListenError {
+    fn from(error: TryReserveError) -> Self {
+        ListenError::OutOfMemory(error)
+    }
+}
+
 pub struct TcpListener {
     listener: Option<TcpStream>,
     endpoint: Option<IpListenEndpoint>,
@@ -97,19 +124,16 @@ impl TcpListener {
         }
     }
 
-    pub fn listen<T: Into<IpListenEndpoint>>(
-        &mut self,
-        endpoint: T,
-    ) -> Result<(), tcp::ListenError> {
-        if self.listener.is_some() {
-            panic!("Allan please add details")
+    pub fn listen<T: Into<IpListenEndpoint>>(&mut self, endpoint: T) -> Result<(), ListenError> {
+        if self.endpoint.is_some() {
+            panic!("TcpListener::listen called on a listener that is already listening")
         }
         self.endpoint = Some(endpoint.into());
         self.listen_inner()
     }
 
-    fn listen_inner(&mut self) -> Result<(), tcp::ListenError> {
-        let mut socket = TcpStream::new();
+    fn listen_inner(&mut self) -> Result<(), ListenError> {
+        let mut socket = TcpStream::try_new()?;
         socket.with_inner(|s| s.listen(self.endpoint.unwrap()))?;
 
         self.listener = Some(socket);
@@ -117,11 +141,19 @@ impl TcpListener {
         Ok(())
     }
 
-    pub async fn accept(&mut self) -> TcpStream {
-        if self.listener.is_none() {
-            panic!("Allan please add details");
+    /// Waits for a connection and hands it over, listening again on a new
+    /// socket for the next one.
+    ///
+    /// If the new socket can't be set up, the connection is still handed
+    /// over and the next call tries again, returning the error if it fails.
+    pub async fn accept(&mut self) -> Result<TcpStream, ListenError> {
+        if self.endpoint.is_none() {
+            panic!("TcpListener::accept called before listen");
         }
         loop {
+            if self.listener.is_none() {
+                self.listen_inner()?;
+            }
             let ready = self
                 .listener
                 .as_mut()
@@ -130,8 +162,9 @@ impl TcpListener {
 
             if ready {
                 let stream = self.listener.take().unwrap();
-                self.listen_inner().unwrap();
-                return stream;
+                // retried by the next call
+                let _ = self.listen_inner();
+                return Ok(stream);
             }
 
             wait_for_socket_state_change().await;
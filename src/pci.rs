@@ -3,10 +3,45 @@ use core::fmt;
 use alloc::vec::Vec;
 use conquer_once::spin::OnceCell;
 use spin::Mutex;
-use x86_64::instructions::port::Port;
+use x86_64::{
+    instructions::{interrupts::without_interrupts, port::Port},
+    PhysAddr,
+};
 
+use crate::apic::{self, MsiMessage};
+use crate::irq::{self, IrqError};
+use crate::memory::mmio::{ioremap, CachePolicy, MmioRegion};
 use crate::println;
 
+const COMMAND: u8 = 0x04;
+const STATUS: u8 = 0x06;
+const CAPABILITIES_POINTER: u8 = 0x34;
+
+const COMMAND_IO: u16 = 1 << 0;
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+/// I/O and memory space decoding.
+const COMMAND_DECODE: u16 = COMMAND_IO | COMMAND_MEMORY;
+const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+
+/// Capability lists live in the 192 bytes after the standard header, so a
+/// longer one must be looping.
+const MAX_CAPABILITIES: usize = 48;
+
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_MSIX: u8 = 0x11;
+
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_MULTIPLE_MESSAGE_ENABLE: u16 = 0b111 << 4;
+const MSI_64BIT: u16 = 1 << 7;
+
+const MSIX_TABLE_SIZE: u16 = 0x7FF;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_ENABLE: u16 = 1 << 15;
+const MSIX_BIR: u32 = 0b111;
+const MSIX_ENTRY_SIZE: usize = 16;
+
 pub struct Register {
     inner: u32,
 }
@@ -43,6 +78,39 @@ pub struct PciDevice {
     pub base_addresses: [u32; 6],
 }
 
+/// A decoded base address register.
+#[derive(Debug, Clone, Copy)]
+pub enum Bar {
+    Memory {
+        addr: PhysAddr,
+        size: usize,
+        prefetchable: bool,
+    },
+    Io {
+        port: u16,
+        size: usize,
+    },
+}
+
+/// An entry of the capability list in a device's configuration space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Where the capability starts in configuration space.
+    pub offset: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiError {
+    /// The device has neither an MSI nor an MSI-X capability.
+    Unsupported,
+    /// The MSI-X table has no such entry.
+    InvalidEntry(u16),
+    /// The MSI-X table isn't in a memory BAR or couldn't be mapped.
+    TableUnavailable,
+    Irq(IrqError),
+}
+
 impl PciDevice {
     fn new(bus: u8, slot: u8, function: u8) -> Self {
         let (id, vendor) = {
@@ -85,11 +153,235 @@ impl PciDevice {
         pci_read(self.bus, self.slot, self.function, register)
     }
 
+    fn read_u8(&self, offset: u8) -> u8 {
+        self.read(offset / 4).byte(offset % 4)
+    }
+
+    fn read_u16(&self, offset: u8) -> u16 {
+        self.read(offset / 4).word(offset % 4 / 2)
+    }
+
+    fn read_u32(&self, offset: u8) -> u32 {
+        self.read(offset / 4).dword()
+    }
+
+    /// Writes the word at `offset` alone. Writing back the other half of the
+    /// dword could clear status bits, which are cleared by writing ones.
+    fn write_u16(&self, offset: u8, value: u16) {
+        pci_write_u16(self.bus, self.slot, self.function, offset, value)
+    }
+
+    fn write_u32(&self, offset: u8, value: u32) {
+        self.write(offset / 4, Register { inner: value });
+    }
+
     pub fn enable_mastering(&self) {
-        let mut command = pci_read(self.bus, self.slot, 0, 1).dword();
-        command |= 0x4;
-        let command = Register { inner: command };
-        self.write(1, command);
+        let command = self.read_u16(COMMAND);
+        self.write_u16(COMMAND, command | COMMAND_BUS_MASTER);
+    }
+
+    /// Decodes BAR `index`, probing its size. Returns `None` for unused BARs.
+    pub fn bar(&self, index: usize) -> Option<Bar> {
+        if index >= 6 {
+            return None;
+        }
+        let register = 0x4 + index as u8;
+        let value = self.read(register).dword();
+        // 64-bit BARs take up the next register too
+        let is_64bit = value & 0x1 == 0 && (value >> 1) & 0x3 == 0x2;
+        let (mask, high_mask) = self.probe_bar(register, is_64bit);
+        if mask == 0 {
+            return None;
+        }
+
+        if value & 0x1 == 0x1 {
+            let size = (!(mask & 0xFFFF_FFFC)).wrapping_add(1) & 0xFFFF;
+            return Some(Bar::Io {
+                port: (value & 0xFFFC) as u16,
+                size: size as usize,
+            });
+        }
+
+        let mut addr = (value & 0xFFFF_FFF0) as u64;
+        let mut mask = (mask & 0xFFFF_FFF0) as u64 | 0xFFFF_FFFF_0000_0000;
+        if is_64bit {
+            addr |= (self.read(register + 1).dword() as u64) << 32;
+            mask = (mask & 0xFFFF_FFFF) | (high_mask as u64) << 32;
+        }
+
+        Some(Bar::Memory {
+            addr: PhysAddr::new(addr),
+            size: (!mask).wrapping_add(1) as usize,
+            prefetchable: value & 0x8 == 0x8,
+        })
+    }
+
+    /// Writes all ones to a BAR, and to its upper half for a 64-bit BAR, and
+    /// reads back which address bits stick.
+    ///
+    /// The device would decode accesses at the all-ones address meanwhile,
+    /// possibly on top of other devices or RAM, so decoding is turned off
+    /// until the BAR is restored.
+    fn probe_bar(&self, register: u8, is_64bit: bool) -> (u32, u32) {
+        let probe = |register| {
+            let original = self.read(register);
+            self.write(register, Register { inner: 0xFFFF_FFFF });
+            let mask = self.read(register).dword();
+            self.write(register, original);
+            mask
+        };
+
+        without_interrupts(|| {
+            let command = self.read_u16(COMMAND);
+            self.write_u16(COMMAND, command & !COMMAND_DECODE);
+            let mask = probe(register);
+            let high_mask = if is_64bit { probe(register + 1) } else { 0 };
+            self.write_u16(COMMAND, command);
+            (mask, high_mask)
+        })
+    }
+
+    /// Maps memory BAR `index` into kernel memory.
+    ///
+    /// Prefetchable BARs are mapped write-through, everything else uncached.
+    pub fn map_bar(&self, index: usize) -> Option<MmioRegion> {
+        match self.bar(index)? {
+            Bar::Memory {
+                addr,
+                size,
+                prefetchable,
+            } => {
+                let cache = if prefetchable {
+                    CachePolicy::WriteThrough
+                } else {
+                    CachePolicy::Uncacheable
+                };
+                ioremap(addr, size, cache).ok()
+            }
+            Bar::Io { .. } => None,
+        }
+    }
+
+    /// Walks the device's capability list.
+    pub fn capabilities(&self) -> impl Iterator<Item = Capability> + '_ {
+        let mut next = if self.read_u16(STATUS) & STATUS_CAPABILITIES_LIST != 0 {
+            self.read_u8(CAPABILITIES_POINTER)
+        } else {
+            0
+        };
+        core::iter::from_fn(move || {
+            // the low two bits are reserved
+            let offset = next & 0xFC;
+            if offset == 0 {
+                return None;
+            }
+            next = self.read_u8(offset + 1);
+            Some(Capability {
+                id: self.read_u8(offset),
+                offset,
+            })
+        })
+        .take(MAX_CAPABILITIES)
+    }
+
+    pub fn capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities().find(|capability| capability.id == id)
+    }
+
+    /// Delivers the device's interrupts to `handler` with `ctx` through
+    /// MSI-X entry 0 or MSI, whichever the device supports, instead of its
+    /// INTx line. Returns the vector they arrive on.
+    pub fn register_msi<T: Sync>(
+        &self,
+        handler: fn(&'static T),
+        ctx: &'static T,
+    ) -> Result<u8, MsiError> {
+        let msix = self.capability(CAP_MSIX).is_some();
+        if !msix && self.capability(CAP_MSI).is_none() {
+            return Err(MsiError::Unsupported);
+        }
+
+        let vector = irq::register_msi(handler, ctx).map_err(MsiError::Irq)?;
+        let message = apic::msi_message(vector);
+        let result = if msix {
+            self.enable_msix(0, message)
+        } else {
+            self.enable_msi(message)
+        };
+        if result.is_err() {
+            irq::unregister_msi(vector);
+        }
+        result.map(|()| vector)
+    }
+
+    /// Makes the device signal its interrupts by writing `message` instead
+    /// of through its INTx line. Only a single MSI vector is enabled.
+    pub fn enable_msi(&self, message: MsiMessage) -> Result<(), MsiError> {
+        let msi = self
+            .capability(CAP_MSI)
+            .ok_or(MsiError::Unsupported)?
+            .offset;
+        let control = self.read_u16(msi + 2);
+
+        self.write_u32(msi + 4, message.address as u32);
+        let data = if control & MSI_64BIT != 0 {
+            self.write_u32(msi + 8, (message.address >> 32) as u32);
+            msi + 12
+        } else {
+            msi + 8
+        };
+        self.write_u16(data, message.data as u16);
+
+        self.write_u16(
+            msi + 2,
+            (control & !MSI_MULTIPLE_MESSAGE_ENABLE) | MSI_ENABLE,
+        );
+        self.disable_intx();
+        Ok(())
+    }
+
+    /// Makes MSI-X table entry `entry` send `message` and switches the
+    /// device from its INTx line to MSI-X.
+    ///
+    /// Entries that aren't set up stay masked. Devices with several entries
+    /// can get one vector each by calling this once per entry.
+    pub fn enable_msix(&self, entry: u16, message: MsiMessage) -> Result<(), MsiError> {
+        let msix = self
+            .capability(CAP_MSIX)
+            .ok_or(MsiError::Unsupported)?
+            .offset;
+        let control = self.read_u16(msix + 2);
+        if entry > control & MSIX_TABLE_SIZE {
+            return Err(MsiError::InvalidEntry(entry));
+        }
+
+        let table = self.read_u32(msix + 4);
+        let addr = match self.bar((table & MSIX_BIR) as usize) {
+            Some(Bar::Memory { addr, .. }) if !addr.is_null() => addr,
+            _ => return Err(MsiError::TableUnavailable),
+        };
+        // the table is only reachable while the device decodes memory
+        let command = self.read_u16(COMMAND);
+        if command & COMMAND_MEMORY == 0 {
+            self.write_u16(COMMAND, command | COMMAND_MEMORY);
+        }
+        let offset = (table & !MSIX_BIR) as usize + entry as usize * MSIX_ENTRY_SIZE;
+        let entry = ioremap(addr + offset, MSIX_ENTRY_SIZE, CachePolicy::Uncacheable)
+            .map_err(|_| MsiError::TableUnavailable)?;
+        entry.write::<u32>(0, message.address as u32);
+        entry.write::<u32>(4, (message.address >> 32) as u32);
+        entry.write::<u32>(8, message.data);
+        // vector control, unmasked
+        entry.write::<u32>(12, 0);
+
+        self.write_u16(msix + 2, (control & !MSIX_FUNCTION_MASK) | MSIX_ENABLE);
+        self.disable_intx();
+        Ok(())
+    }
+
+    fn disable_intx(&self) {
+        let command = self.read_u16(COMMAND);
+        self.write_u16(COMMAND, command | COMMAND_INTX_DISABLE);
     }
 
     pub fn io_base(&self) -> u16 {
@@ -176,6 +468,24 @@ fn pci_write(bus: u8, slot: u8, func: u8, register: u8, data: Register) {
     }
 }
 
+/// Writes the word at byte `offset` with a 16-bit access, leaving the rest
+/// of the dword alone.
+fn pci_write_u16(bus: u8, slot: u8, func: u8, offset: u8, value: u16) {
+    let bus = bus as u32;
+    let slot = slot as u32;
+    let func = func as u32;
+
+    let address = (bus << 16) | (slot << 11) | (func << 8) | (offset & 0xFC) as u32 | 0x80000000;
+
+    let mut control: Port<u32> = Port::new(0xCF8);
+    let mut data_port: Port<u16> = Port::new(0xCFC + (offset & 0x2) as u16);
+
+    unsafe {
+        control.write(address);
+        data_port.write(value);
+    }
+}
+
 pub fn get_device(vendor: u16, id: u16) -> Option<PciDevice> {
     for device in PCI_DEVICES.get().unwrap().lock().iter() {
         if device.vendor == vendor && device.id == id {
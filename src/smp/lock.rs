@@ -0,0 +1,96 @@
+use core::{
+    cell::UnsafeCell,
+    hint::spin_loop,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::{percpu, tlb};
+
+const NO_OWNER: usize = usize::MAX;
+
+/// A spin lock that knows which CPU holds it.
+///
+/// Fault handlers check `is_locked_here` before taking one, since the code
+/// they interrupted can't let go of a lock, while another CPU will. Waiting
+/// for the lock keeps handling TLB shootdowns, so it is fine to wait with
+/// interrupts disabled.
+pub struct CpuMutex<T> {
+    /// Index of the holding CPU in `smp::cpus`, or `NO_OWNER`.
+    owner: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for CpuMutex<T> {}
+
+pub struct CpuMutexGuard<'a, T> {
+    mutex: &'a CpuMutex<T>,
+}
+
+impl<T> CpuMutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            owner: AtomicUsize::new(NO_OWNER),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> CpuMutexGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            tlb::handle_request();
+            spin_loop();
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<CpuMutexGuard<'_, T>> {
+        self.owner
+            .compare_exchange(NO_OWNER, this_cpu(), Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| CpuMutexGuard { mutex: self })
+    }
+
+    /// Whether the calling CPU holds the lock.
+    pub fn is_locked_here(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) == this_cpu()
+    }
+}
+
+impl<T> Deref for CpuMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for CpuMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for CpuMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.owner.store(NO_OWNER, Ordering::Release);
+    }
+}
+
+/// Only the bootstrap processor runs before the per-CPU data is set up.
+fn this_cpu() -> usize {
+    percpu::try_current().map_or(0, |cpu| cpu.id())
+}
+
+#[test_case]
+fn test_locked_here() {
+    let mutex = CpuMutex::new(0);
+    let mut guard = mutex.lock();
+    *guard += 1;
+    assert!(mutex.is_locked_here());
+    assert!(mutex.try_lock().is_none());
+    drop(guard);
+    assert!(!mutex.is_locked_here());
+    assert_eq!(*mutex.lock(), 1);
+}
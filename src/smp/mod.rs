@@ -0,0 +1,236 @@
+use core::{
+    arch::global_asm,
+    hint::spin_loop,
+    ptr::addr_of,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use acpi::platform::ProcessorState;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{Mapper, Page, PageTableFlags, PhysFrame},
+    VirtAddr,
+};
+
+use crate::{
+    apic::{self, lapic},
+    gdt, interrupts,
+    memory::{phys_to_virt, stack::KernelStack, BootInfoFrameAllocator, FRAME_ALLOCATOR, MAPPER},
+    println,
+    task::executor::Executor,
+    time,
+};
+
+pub mod lock;
+pub mod percpu;
+pub mod tlb;
+
+global_asm!(include_str!("trampoline.s"), options(att_syntax));
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_params: u8;
+}
+
+const AP_STACK_SIZE: usize = 64 * 1024;
+
+/// Initial count of the timer that wakes idle application processors, about
+/// 10ms with QEMU's 1 GHz APIC bus.
+const WAKEUP_TIMER_COUNT: u32 = 625_000;
+
+/// How long an AP gets to come online before it is given up on, in ms.
+const STARTUP_TIMEOUT: i64 = 100;
+
+static TRAMPOLINE: OnceCell<PhysFrame> = OnceCell::uninit();
+static CPUS: OnceCell<Vec<Cpu>> = OnceCell::uninit();
+
+/// A processor listed in the MADT. The bootstrap processor comes first.
+pub struct Cpu {
+    pub apic_id: u8,
+    online: AtomicBool,
+    /// Set while another CPU waits for this one to flush its TLB.
+    flush_pending: AtomicBool,
+}
+
+impl Cpu {
+    fn new(apic_id: u8, online: bool) -> Self {
+        Cpu {
+            apic_id,
+            online: AtomicBool::new(online),
+            flush_pending: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+}
+
+/// The values `ap_params` in the trampoline is filled with.
+#[repr(C)]
+struct TrampolineParams {
+    cr3: u64,
+    stack_top: u64,
+    cpu: u64,
+    entry: u64,
+}
+
+/// Sets aside the page below 1 MiB that application processors start in.
+///
+/// Low frames are handed out first, so this has to run before anything else
+/// allocates.
+pub fn reserve_trampoline(frame_allocator: &mut BootInfoFrameAllocator) {
+    if let Some(range) = frame_allocator.allocate_contiguous_constrained(1, 4096, 0xF_FFFF) {
+        TRAMPOLINE.init_once(|| range.start);
+    }
+}
+
+/// Starts every application processor listed in the MADT, one after the
+/// other, and lets it join the scheduler.
+///
+/// This waits on the PIT, so interrupts have to be enabled.
+pub fn init() {
+    let bsp_id = if apic::is_enabled() { lapic::id() } else { 0 };
+    let application_processors: Vec<u8> = crate::acpi::platform_info()
+        .filter(|_| apic::is_enabled())
+        .and_then(|info| info.processor_info)
+        .map(|info| {
+            info.application_processors
+                .iter()
+                .filter(|ap| matches!(ap.state, ProcessorState::WaitingForSipi))
+                .map(|ap| ap.local_apic_id as u8)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut list = Vec::with_capacity(application_processors.len() + 1);
+    list.push(Cpu::new(bsp_id, true));
+    list.extend(application_processors.iter().map(|&id| Cpu::new(id, false)));
+    CPUS.init_once(|| list);
+    if application_processors.is_empty() {
+        return;
+    }
+
+    let Some(&trampoline) = TRAMPOLINE.get() else {
+        println!("SMP: no page below 1 MiB for the trampoline");
+        return;
+    };
+    let (l4_frame, _) = Cr3::read();
+    if l4_frame.start_address().as_u64() > u32::MAX as u64 {
+        println!("SMP: page tables out of reach of the trampoline");
+        return;
+    }
+    if !identity_map(trampoline) {
+        println!("SMP: failed to map the trampoline");
+        return;
+    }
+    let params = unsafe { copy_trampoline(trampoline) };
+
+    for (index, cpu) in cpus().iter().enumerate().skip(1) {
+        let Some(stack) = KernelStack::new(AP_STACK_SIZE) else {
+            println!("SMP: no stack for CPU {}", index);
+            break;
+        };
+        unsafe {
+            params.write_volatile(TrampolineParams {
+                cr3: l4_frame.start_address().as_u64(),
+                stack_top: stack.top().as_u64(),
+                cpu: index as u64,
+                entry: ap_main as usize as u64,
+            });
+        }
+        // the AP runs on the stack until it's turned off
+        core::mem::forget(stack);
+
+        start(cpu, trampoline);
+        if !cpu.is_online() {
+            println!("SMP: CPU {} (APIC ID {}) didn't start", index, cpu.apic_id);
+        }
+    }
+
+    unmap(trampoline);
+}
+
+/// Every processor found by `init`.
+pub fn cpus() -> &'static [Cpu] {
+    CPUS.get().map(|cpus| cpus.as_slice()).unwrap_or(&[])
+}
+
+/// Sends INIT, SIPI, SIPI and waits for the CPU to report itself online.
+fn start(cpu: &Cpu, trampoline: PhysFrame) {
+    let page = (trampoline.start_address().as_u64() >> 12) as u8;
+
+    lapic::send_init(cpu.apic_id);
+    delay_ms(10);
+    for _ in 0..2 {
+        lapic::send_startup(cpu.apic_id, page);
+        delay_ms(1);
+        if cpu.is_online() {
+            return;
+        }
+    }
+
+    let start = time::time_ms();
+    while !cpu.is_online() && time::time_ms() - start < STARTUP_TIMEOUT {
+        spin_loop();
+    }
+}
+
+fn delay_ms(ms: i64) {
+    let start = time::time_ms();
+    while time::time_ms() - start < ms {
+        spin_loop();
+    }
+}
+
+/// Copies the trampoline into `frame` and returns where its parameters ended
+/// up.
+unsafe fn copy_trampoline(frame: PhysFrame) -> *mut TrampolineParams {
+    let start = addr_of!(ap_trampoline_start);
+    let len = addr_of!(ap_trampoline_end) as usize - start as usize;
+    assert!(len <= 4096, "AP trampoline larger than a page");
+
+    let dest = phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+    core::ptr::copy_nonoverlapping(start, dest, len);
+    dest.add(addr_of!(ap_params) as usize - start as usize)
+        .cast()
+}
+
+/// The trampoline turns on paging while running from `frame`, so the frame
+/// has to be mapped at its own address.
+fn identity_map(frame: PhysFrame) -> bool {
+    let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    let mut mapper = MAPPER.get().unwrap().lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator) }
+        .map(|flush| flush.flush())
+        .is_ok()
+}
+
+fn unmap(frame: PhysFrame) {
+    let page: Page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    if let Ok((_, flush)) = MAPPER.get().unwrap().lock().unmap(page) {
+        flush.ignore();
+        tlb::flush(page.start_address());
+    }
+}
+
+/// Where application processors continue after the trampoline, on their own
+/// kernel stack.
+extern "C" fn ap_main(cpu: usize) -> ! {
+    gdt::init_ap();
+    percpu::init(cpu);
+    interrupts::load_idt();
+    lapic::enable();
+    lapic::start_timer(WAKEUP_TIMER_COUNT);
+    cpus()[cpu].online.store(true, Ordering::SeqCst);
+    // shootdowns skipped this CPU until now, so drop whatever it has cached
+    x86_64::instructions::tlb::flush_all();
+    x86_64::instructions::interrupts::enable();
+
+    Executor::new().run();
+}
@@ -0,0 +1,185 @@
+use alloc::{boxed::Box, sync::Arc};
+use core::{
+    arch::asm,
+    fmt, ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+use crossbeam_queue::ArrayQueue;
+use x86_64::{
+    instructions::segmentation::GS,
+    registers::model_specific::{GsBase, KernelGsBase},
+    structures::idt::{InterruptStackFrame, InterruptStackFrameValue},
+    VirtAddr,
+};
+
+use crate::task::TaskId;
+
+const NO_TASK: u64 = u64::MAX;
+const RUN_QUEUE_SIZE: usize = 100;
+
+/// Data private to one CPU, found through its GS base.
+///
+/// While the CPU runs kernel code, GS base points here and `KernelGsBase`
+/// holds whatever user code uses. Code entered from user mode has to
+/// `swapgs` before touching this, which `InterruptScope` takes care of.
+#[repr(C)]
+pub struct PerCpu {
+    // has to stay first, `current` loads it from `gs:0`
+    this: *const PerCpu,
+    id: usize,
+    current_task: AtomicU64,
+    interrupt_depth: AtomicUsize,
+    /// Stack frame of the innermost interrupt, null outside of interrupts.
+    interrupt_frame: AtomicPtr<InterruptStackFrameValue>,
+    run_queue: Arc<ArrayQueue<TaskId>>,
+}
+
+impl PerCpu {
+    /// Index of the CPU in `smp::cpus`, 0 for the bootstrap processor.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// The task the CPU's executor is polling, if any.
+    pub fn current_task(&self) -> Option<TaskId> {
+        match self.current_task.load(Ordering::Relaxed) {
+            NO_TASK => None,
+            id => Some(TaskId::from_u64(id)),
+        }
+    }
+
+    pub(crate) fn set_current_task(&self, task: Option<TaskId>) {
+        let id = task.map_or(NO_TASK, TaskId::as_u64);
+        self.current_task.store(id, Ordering::Relaxed);
+    }
+
+    /// How many interrupt handlers are running on the CPU, nested in each
+    /// other.
+    pub fn interrupt_depth(&self) -> usize {
+        self.interrupt_depth.load(Ordering::Relaxed)
+    }
+
+    pub fn in_interrupt(&self) -> bool {
+        self.interrupt_depth() > 0
+    }
+
+    /// The stack frame of the innermost interrupt handler running on the
+    /// CPU, for handlers that aren't passed it.
+    pub fn interrupt_frame(&self) -> Option<InterruptStackFrameValue> {
+        let frame = self.interrupt_frame.load(Ordering::Relaxed);
+        (!frame.is_null()).then(|| unsafe { *frame })
+    }
+
+    /// Ready tasks of the CPU's executor.
+    pub(crate) fn run_queue(&self) -> &Arc<ArrayQueue<TaskId>> {
+        &self.run_queue
+    }
+}
+
+/// Sets up the per-CPU data of the calling CPU, which is CPU `id`.
+///
+/// The data is never freed, so this must only be called once per CPU.
+pub fn init(id: usize) {
+    let per_cpu = Box::leak(Box::new(PerCpu {
+        this: core::ptr::null(),
+        id,
+        current_task: AtomicU64::new(NO_TASK),
+        interrupt_depth: AtomicUsize::new(0),
+        interrupt_frame: AtomicPtr::new(ptr::null_mut()),
+        run_queue: Arc::new(ArrayQueue::new(RUN_QUEUE_SIZE)),
+    }));
+    let this: *const PerCpu = per_cpu;
+    per_cpu.this = this;
+
+    GsBase::write(VirtAddr::from_ptr(per_cpu));
+    KernelGsBase::write(VirtAddr::zero());
+}
+
+/// The calling CPU's data.
+///
+/// `init` must have run on this CPU already.
+pub fn current() -> &'static PerCpu {
+    let this: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) this, options(nostack, preserves_flags, readonly));
+        &*this
+    }
+}
+
+/// The calling CPU's data, or `None` if `init` hasn't run on this CPU yet.
+///
+/// This reads the GS base MSR first, so it is slower than `current` but safe
+/// to use from fault handlers.
+pub fn try_current() -> Option<&'static PerCpu> {
+    (!GsBase::read().is_null()).then(current)
+}
+
+/// Displays the calling CPU and the task it is polling, for crash reports.
+pub struct CurrentContext;
+
+impl fmt::Display for CurrentContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match try_current() {
+            Some(cpu) => match cpu.current_task() {
+                Some(task) => write!(f, "CPU {}, task {}", cpu.id(), task.as_u64()),
+                None => write!(f, "CPU {}, no task", cpu.id()),
+            },
+            None => write!(f, "CPU not initialized"),
+        }
+    }
+}
+
+/// Marks the current CPU as running an interrupt handler for as long as it
+/// is alive.
+///
+/// Interrupts coming from user mode find the user's GS base loaded, so the
+/// scope swaps in the kernel's on entry and back on exit.
+pub struct InterruptScope {
+    from_user: bool,
+    /// Frame of the interrupt this one interrupted, if any.
+    outer_frame: *mut InterruptStackFrameValue,
+}
+
+impl InterruptScope {
+    pub fn enter(stack_frame: &InterruptStackFrame) -> Self {
+        let from_user = stack_frame.code_segment & 0b11 != 0;
+        if from_user {
+            unsafe { GS::swap() };
+        }
+        let mut outer_frame = ptr::null_mut();
+        if let Some(per_cpu) = try_current() {
+            per_cpu.interrupt_depth.fetch_add(1, Ordering::Relaxed);
+            let frame: *const InterruptStackFrameValue = &**stack_frame;
+            outer_frame = per_cpu
+                .interrupt_frame
+                .swap(frame.cast_mut(), Ordering::Relaxed);
+        }
+        InterruptScope {
+            from_user,
+            outer_frame,
+        }
+    }
+}
+
+impl Drop for InterruptScope {
+    fn drop(&mut self) {
+        if let Some(per_cpu) = try_current() {
+            per_cpu.interrupt_depth.fetch_sub(1, Ordering::Relaxed);
+            per_cpu
+                .interrupt_frame
+                .store(self.outer_frame, Ordering::Relaxed);
+        }
+        if self.from_user {
+            unsafe { GS::swap() };
+        }
+    }
+}
+
+#[test_case]
+fn test_per_cpu_data() {
+    let per_cpu = current();
+    assert_eq!(per_cpu.id(), 0);
+    assert!(!per_cpu.in_interrupt());
+    assert!(per_cpu.interrupt_frame().is_none());
+    assert!(core::ptr::eq(per_cpu, try_current().unwrap()));
+}
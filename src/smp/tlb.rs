@@ -0,0 +1,97 @@
+use core::{
+    hint::spin_loop,
+    sync::atomic::{fence, AtomicU64, Ordering},
+};
+
+use spin::{Mutex, MutexGuard};
+use x86_64::{instructions::tlb, VirtAddr};
+
+use super::{cpus, percpu, Cpu};
+use crate::apic::lapic;
+
+/// `REQUEST` asking for the whole TLB to be flushed.
+const FLUSH_ALL: u64 = u64::MAX;
+
+/// What the CPU holding `SHOOTDOWN` asks the others to flush, the address of
+/// a page or `FLUSH_ALL`.
+static REQUEST: AtomicU64 = AtomicU64::new(FLUSH_ALL);
+
+/// Held while a request is out, so that there's only one at a time.
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+
+/// Flushes the page at `addr` from the TLB of every online CPU.
+///
+/// Whoever unmaps a page or takes permissions away has to call this before
+/// the frame is reused, the other CPUs keep using a stale translation
+/// otherwise. Returns once every CPU has flushed.
+pub fn flush(addr: VirtAddr) {
+    tlb::flush(addr);
+    shootdown(addr.as_u64());
+}
+
+/// Flushes the whole TLB of every online CPU, like `flush`.
+pub fn flush_all() {
+    tlb::flush_all();
+    shootdown(FLUSH_ALL);
+}
+
+/// Flushes what another CPU asked the calling one to flush, if anything.
+///
+/// This runs in the handler of `lapic::TLB_FLUSH_VECTOR`. Loops waiting on
+/// another CPU with interrupts disabled call it too, as that CPU may be
+/// waiting on the flush.
+pub fn handle_request() {
+    let Some(cpu) = this_cpu() else {
+        return;
+    };
+    if cpu.flush_pending.load(Ordering::SeqCst) {
+        match REQUEST.load(Ordering::SeqCst) {
+            FLUSH_ALL => tlb::flush_all(),
+            addr => tlb::flush(VirtAddr::new(addr)),
+        }
+        cpu.flush_pending.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Has every other online CPU flush `request` and waits until they did.
+fn shootdown(request: u64) {
+    // the page table change has to be visible before checking who's online
+    fence(Ordering::SeqCst);
+    let Some(this) = this_cpu() else {
+        return;
+    };
+    let others = || {
+        cpus()
+            .iter()
+            .filter(move |cpu| !core::ptr::eq(*cpu, this) && cpu.is_online())
+    };
+    if others().next().is_none() {
+        return;
+    }
+
+    let _guard = lock();
+    REQUEST.store(request, Ordering::SeqCst);
+    for cpu in others() {
+        cpu.flush_pending.store(true, Ordering::SeqCst);
+        lapic::send_fixed(cpu.apic_id, lapic::TLB_FLUSH_VECTOR);
+    }
+    while others().any(|cpu| cpu.flush_pending.load(Ordering::SeqCst)) {
+        spin_loop();
+    }
+}
+
+/// Takes `SHOOTDOWN`, flushing for whoever holds it in the meantime, who
+/// would never let go otherwise if interrupts are disabled here.
+fn lock() -> MutexGuard<'static, ()> {
+    loop {
+        if let Some(guard) = SHOOTDOWN.try_lock() {
+            return guard;
+        }
+        handle_request();
+        spin_loop();
+    }
+}
+
+fn this_cpu() -> Option<&'static Cpu> {
+    cpus().get(percpu::try_current()?.id())
+}
@@ -0,0 +1,89 @@
+# Entry point of the application processors.
+#
+# The bootstrap processor copies everything between `ap_trampoline_start`
+# and `ap_trampoline_end` to a page below 1 MiB, identity maps that page and
+# fills in `ap_params`. A startup IPI then starts the AP in real mode at the
+# start of the page, with CS pointing at it. From there the trampoline goes
+# straight to long mode on the kernel's page tables and calls the entry point
+# with the CPU index as its argument.
+
+.section .text
+.balign 16
+.code16
+.global ap_trampoline_start
+ap_trampoline_start:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+
+    # the code can run from any page, so fix up the absolute addresses
+    xor %ebx, %ebx
+    mov %cs, %bx
+    shl $4, %ebx
+    mov %ebx, %eax
+    add $(ap_gdt - ap_trampoline_start), %eax
+    mov %eax, ap_gdtr - ap_trampoline_start + 2
+    mov %ebx, %eax
+    add $(ap_long_mode - ap_trampoline_start), %eax
+    mov %eax, ap_far_jump_target - ap_trampoline_start
+    lgdtl ap_gdtr - ap_trampoline_start
+
+    # PAE
+    mov %cr4, %eax
+    or $(1 << 5), %eax
+    mov %eax, %cr4
+
+    movl ap_params - ap_trampoline_start, %eax
+    mov %eax, %cr3
+
+    # EFER.LME and EFER.NXE
+    mov $0xC0000080, %ecx
+    rdmsr
+    or $((1 << 8) | (1 << 11)), %eax
+    wrmsr
+
+    # paging, write protection and protected mode at once
+    mov %cr0, %eax
+    or $((1 << 31) | (1 << 16) | 1), %eax
+    mov %eax, %cr0
+
+    # ljmpl $0x08, $ap_long_mode
+    .byte 0x66, 0xEA
+ap_far_jump_target:
+    .long 0
+    .word 0x08
+
+.code64
+ap_long_mode:
+    xor %ax, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov %ax, %fs
+    mov %ax, %gs
+
+    mov ap_params + 8(%rip), %rsp
+    mov ap_params + 16(%rip), %rdi
+    mov ap_params + 24(%rip), %rax
+    # ends backtraces
+    xor %rbp, %rbp
+    call *%rax
+    ud2
+
+.balign 8
+ap_gdt:
+    .quad 0
+    .quad 0x00AF9A000000FFFF # 64-bit code
+ap_gdtr:
+    .word ap_gdtr - ap_gdt - 1
+    .long 0
+
+# cr3, stack top, CPU index, entry point
+.balign 8
+.global ap_params
+ap_params:
+    .quad 0, 0, 0, 0
+
+.global ap_trampoline_end
+ap_trampoline_end:
@@ -6,6 +6,8 @@ use core::task::{Context, Poll, Waker};
 use crossbeam_queue::ArrayQueue;
 use futures_util::Future;
 
+use crate::smp::percpu;
+
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
     task_queue: Arc<ArrayQueue<TaskId>>,
@@ -37,12 +39,17 @@ pub fn spawn(task: impl Future<Output = ()> + Send + 'static) {
 }
 
 impl Executor {
+    /// Creates an executor for the current CPU, running the tasks in its
+    /// per-CPU run queue.
+    ///
+    /// Every executor takes spawned tasks from the same queue, so whichever
+    /// CPU is free first picks them up. A task then stays on that CPU.
     pub fn new() -> Self {
-        let incoming_tasks = Arc::new(ArrayQueue::new(100));
-        TASK_SPAWNER.init_once(|| TaskSpawner::new(incoming_tasks.clone()));
+        TASK_SPAWNER.init_once(|| TaskSpawner::new(Arc::new(ArrayQueue::new(100))));
+        let incoming_tasks = TASK_SPAWNER.get().unwrap().task_queue.clone();
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            task_queue: percpu::current().run_queue().clone(),
             waker_cache: BTreeMap::new(),
             incoming_tasks,
         }
@@ -77,7 +84,11 @@ impl Executor {
                 .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
             let mut context = Context::from_waker(waker);
             //println!("Executing task {:?}", task.id);
-            match task.poll(&mut context) {
+            let per_cpu = percpu::current();
+            per_cpu.set_current_task(Some(task_id));
+            let poll = task.poll(&mut context);
+            per_cpu.set_current_task(None);
+            match poll {
                 Poll::Ready(()) => {
                     // task done -> remove it and its cached waker
                     tasks.remove(&task_id);
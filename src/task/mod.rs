@@ -11,13 +11,21 @@ pub mod network;
 pub mod shell;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
 
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    pub(crate) fn from_u64(id: u64) -> Self {
+        TaskId(id)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
 }
 
 pub struct Task {
@@ -10,17 +10,18 @@ use smoltcp::{
 };
 
 use crate::{
-    backspace,
+    allocator, backspace, interrupts,
+    memory::{regions, swap, FRAME_ALLOCATOR},
     networking::{
-        get_interface,
+        dropped_packets, get_interface,
         socket::{
             icmp::IcmpSocket,
             tcp::{TcpListener, TcpStream},
         },
     },
-    print, println,
+    print, println, smp,
     task::executor::spawn,
-    time::{sleep, time_ms},
+    time::{sleep, time, time_ms},
 };
 
 use super::keyboard::KeyStream;
@@ -103,6 +104,10 @@ pub async fn shell() {
                         None => println!("Missing argument"),
                     };
                 }
+                "meminfo" => meminfo(),
+                "memmap" => memmap(),
+                "cpus" => cpus(),
+                "irqstat" => irqstat(),
                 _ => {
                     println!("Unrecognized commmand: {}", command)
                 }
@@ -113,9 +118,112 @@ pub async fn shell() {
     }
 }
 
+fn meminfo() {
+    let heap = allocator::stats();
+    println!(
+        "Heap: {} bytes allocated, peak {} bytes",
+        heap.allocated, heap.peak
+    );
+    println!(
+        "      {} of {} bytes mapped, {} free, largest free block unavailable",
+        heap.size, heap.max_size, heap.free
+    );
+    println!(
+        "      {} allocations, {} frees, {} failures",
+        heap.allocations, heap.frees, heap.failures
+    );
+
+    let (total, used, free) = {
+        let frames = FRAME_ALLOCATOR.get().unwrap().lock();
+        (
+            frames.total_frames(),
+            frames.used_frames(),
+            frames.free_frames(),
+        )
+    };
+    println!(
+        "Frames: {} of {} used, {} free ({} KiB)",
+        used,
+        total,
+        free,
+        free * 4
+    );
+
+    if let Some(swap) = swap::stats() {
+        println!(
+            "Swap: {} of {} slots used ({} KiB free)",
+            swap.used,
+            swap.slots,
+            (swap.slots - swap.used) * 4
+        );
+    }
+
+    println!(
+        "Network: {} received packets dropped for lack of memory",
+        dropped_packets()
+    );
+}
+
+fn memmap() {
+    for region in regions::regions() {
+        println!(
+            "{:#014x}-{:#014x} {:>10} KiB  {:?}",
+            region.start.as_u64(),
+            region.end.as_u64(),
+            region.size() / 1024,
+            region.kind
+        );
+    }
+
+    let totals = regions::totals();
+    println!(
+        "Usable: {} KiB, reserved: {} KiB",
+        totals.usable / 1024,
+        totals.reserved / 1024
+    );
+}
+
+fn cpus() {
+    for (index, cpu) in smp::cpus().iter().enumerate() {
+        let state = if cpu.is_online() { "online" } else { "offline" };
+        println!("CPU {}: APIC ID {}, {}", index, cpu.apic_id, state);
+    }
+}
+
+fn irqstat() {
+    let uptime = time();
+    println!("Vector      Count     Per s  Source");
+    for vector in 0..=u8::MAX {
+        let count = interrupts::interrupt_count(vector);
+        if count == 0 {
+            continue;
+        }
+        let rate = if uptime > 0.0 {
+            count as f64 / uptime
+        } else {
+            0.0
+        };
+        println!(
+            "{:>6} {:>10} {:>9.1}  {}",
+            vector,
+            count,
+            rate,
+            interrupts::VectorName(vector)
+        );
+    }
+    println!(
+        "Spurious 8259 IRQs: {}",
+        interrupts::spurious_pic_irq_count()
+    );
+    println!("Uptime: {:.1} s", uptime);
+}
+
 async fn ping(remote_addr: IpAddress) {
     let interface = get_interface(0).unwrap();
-    let mut icmp_socket = IcmpSocket::new();
+    let Ok(mut icmp_socket) = IcmpSocket::try_new() else {
+        println!("Out of memory for the ICMP socket");
+        return;
+    };
 
     let mut echo_payload = [0xffu8; 40];
     let ident = 0x22b;
@@ -155,7 +263,10 @@ async fn ping(remote_addr: IpAddress) {
 
 async fn connect(remote_addr: IpAddress, text: String) {
     let mut interface = get_interface(0).unwrap();
-    let mut socket = TcpStream::new();
+    let Ok(mut socket) = TcpStream::try_new() else {
+        println!("Out of memory for the TCP socket");
+        return;
+    };
 
     socket
         .connect(&mut interface, remote_addr, 80)
@@ -172,10 +283,19 @@ async fn listen(port: u16) {
     //let mut interface = get_interface(0).unwrap();
     let mut listener = TcpListener::new();
     println!("Listening on {port}");
-    listener.listen(port).unwrap();
+    if let Err(error) = listener.listen(port) {
+        println!("Failed to listen: {:?}", error);
+        return;
+    }
 
     loop {
-        let mut stream = listener.accept().await;
+        let mut stream = match listener.accept().await {
+            Ok(stream) => stream,
+            Err(error) => {
+                println!("Failed to listen for the next client: {:?}", error);
+                return;
+            }
+        };
         println!("New client!");
         spawn(async move {
             loop {
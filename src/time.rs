@@ -9,15 +9,9 @@ use alloc::{sync::Arc, vec::Vec};
 use futures_util::{task::AtomicWaker, Future};
 use generic_once_cell::Lazy;
 use spin::Mutex;
-use x86_64::{
-    instructions::{interrupts, port::Port},
-    structures::idt::InterruptStackFrame,
-};
+use x86_64::instructions::{interrupts, port::Port};
 
-use crate::{
-    interrupts::{InterruptIndex, PICS},
-    print, println,
-};
+use crate::{print, println, smp::percpu};
 
 pub const PIT_FREQUENCY: f64 = 3_579_545.0 / 3.0; // 1_193_181.666 Hz
 pub const PIT_DIVIDER: usize = 1193;
@@ -125,7 +119,7 @@ fn wake_sleepers() {
     });
 }
 
-pub extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+pub fn timer_irq_handler(_: &()) {
     CLOCK.fetch_add(1, Ordering::Relaxed);
 
     //print!(".");
@@ -135,11 +129,6 @@ pub extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptSta
     .get()
     .expect("TASK_SPAWNER not initialized")
     .spawn(Task::new(wake_sleepers()));*/
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
 }
 
 pub fn set_pit_frequency_divider(divider: u16, channel: u8) {
@@ -157,17 +146,14 @@ pub fn set_pit_frequency_divider(divider: u16, channel: u8) {
     });
 }
 
-pub extern "x86-interrupt" fn rtc_interrupt_handler(stack_frame: InterruptStackFrame) {
+pub fn rtc_irq_handler(_: &()) {
     print!("+");
     static SHOWN: AtomicBool = AtomicBool::new(false);
     let val = SHOWN.load(Ordering::Relaxed);
     if !val {
-        println!("{:?}", stack_frame);
+        if let Some(stack_frame) = percpu::current().interrupt_frame() {
+            println!("{:?}", stack_frame);
+        }
         SHOWN.store(true, Ordering::Relaxed);
     }
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::RealTimeClock.as_u8());
-    }
 }
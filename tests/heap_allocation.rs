@@ -27,7 +27,7 @@ fn panic(info: &PanicInfo) -> ! {
     blog_os::test_panic_handler(info)
 }
 
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, vec, vec::Vec};
 
 #[test_case]
 fn simple_allocation() {
@@ -47,7 +47,10 @@ fn large_vec() {
     assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
 }
 
-use blog_os::{allocator::HEAP_SIZE, hlt_loop};
+use blog_os::{
+    allocator::{self, HEAP_SIZE},
+    hlt_loop,
+};
 
 #[test_case]
 fn many_boxes() {
@@ -56,3 +59,23 @@ fn many_boxes() {
         assert_eq!(*x, i);
     }
 }
+
+#[test_case]
+fn grows_past_initial_size() {
+    let vec = vec![1u8; 2 * HEAP_SIZE];
+    assert!(vec.iter().all(|&b| b == 1));
+    assert!(allocator::heap().size() > 2 * HEAP_SIZE);
+}
+
+#[test_case]
+fn stats_track_allocations() {
+    let before = allocator::stats();
+    let value = core::hint::black_box(Box::new([0u8; 64]));
+    let during = allocator::stats();
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert_eq!(during.allocated, before.allocated + 64);
+    drop(value);
+    let after = allocator::stats();
+    assert_eq!(after.frees, before.frees + 1);
+    assert_eq!(after.allocated, before.allocated);
+}
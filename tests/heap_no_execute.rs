@@ -0,0 +1,70 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use core::panic::PanicInfo;
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+use blog_os::allocator::{HEAP_MAX_SIZE, HEAP_START};
+use blog_os::{exit_qemu, serial_println, QemuExitCode};
+use blog_os::{hlt_loop, serial_print};
+use bootloader::{entry_point, BootInfo};
+use generic_once_cell::Lazy;
+use spin::Mutex;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let addr = Cr2::read().as_u64() as usize;
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+        && (HEAP_START..HEAP_START + HEAP_MAX_SIZE).contains(&addr)
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!(
+            "Error: unexpected page fault at {:#x} ({:?})\n",
+            addr,
+            error_code
+        );
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hlt_loop()
+}
+
+static TEST_IDT: Lazy<Mutex<()>, InterruptDescriptorTable> = Lazy::new(|| {
+    let mut idt = InterruptDescriptorTable::new();
+    idt.page_fault.set_handler_fn(test_page_fault_handler);
+    idt
+});
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("heap_no_execute::jump_into_heap...\t");
+
+    blog_os::init(boot_info);
+    x86_64::instructions::interrupts::disable();
+    TEST_IDT.load();
+
+    // a lone `ret`, which would return right away if the heap were executable
+    let code = Box::new([0xc3u8]);
+    let function: extern "C" fn() = unsafe { core::mem::transmute(code.as_ptr()) };
+    function();
+
+    serial_println!("[failed]\n");
+    serial_println!("Error: executed code on the heap\n");
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop()
+}
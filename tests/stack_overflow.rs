@@ -9,18 +9,30 @@ fn panic(info: &PanicInfo) -> ! {
     blog_os::test_panic_handler(info)
 }
 
+use blog_os::memory::stack::{is_stack_guard, KernelStack};
 use blog_os::{exit_qemu, serial_println, QemuExitCode};
 use blog_os::{hlt_loop, serial_print};
+use bootloader::{entry_point, BootInfo};
 use generic_once_cell::Lazy;
 use spin::Mutex;
+use x86_64::registers::control::Cr2;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
 extern "x86-interrupt" fn test_double_fault_handler(
     _stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    serial_println!("[ok]");
-    exit_qemu(QemuExitCode::Success);
+    if is_stack_guard(Cr2::read()) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!(
+            "Error: fault at {:?} not detected as stack overflow\n",
+            Cr2::read()
+        );
+        exit_qemu(QemuExitCode::Failed);
+    }
     hlt_loop()
 }
 
@@ -34,16 +46,30 @@ static TEST_IDT: Lazy<Mutex<()>, InterruptDescriptorTable> = Lazy::new(|| {
     idt
 });
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
     serial_print!("stack_overflow::stack_overflow...\t");
 
-    blog_os::gdt::init();
+    blog_os::init(boot_info);
+    x86_64::instructions::interrupts::disable();
     init_test_idt();
 
-    // trigger a stack overflow
-    stack_overflow();
+    // overflow a stack with a guard page below it
+    let stack = KernelStack::new(4 * 4096).unwrap();
+    unsafe {
+        core::arch::asm!(
+            "mov rsp, {top}",
+            "call {overflow}",
+            top = in(reg) stack.top().as_u64(),
+            overflow = sym overflow,
+            options(noreturn),
+        );
+    }
+}
 
+extern "C" fn overflow() -> ! {
+    stack_overflow();
     panic!("Execution continued after stack overflow");
 }
 
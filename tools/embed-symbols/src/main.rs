@@ -0,0 +1,203 @@
+//! Post-link step of the kernel, used as its cargo runner.
+//!
+//! Fills the `.kernel_symbols` section of a kernel ELF file with the
+//! kernel's function symbols, sorted by address, then hands the file on to
+//! `bootimage runner`. The section is loaded with the rest of the kernel, so
+//! backtraces can name functions whatever the bootloader does with the file.
+//!
+//! The table layout has to match `src/backtrace/symbols.rs`: a magic number
+//! and a symbol count, then for every symbol its address, its size and the
+//! offset of its NUL-terminated name, then the names.
+
+use std::{
+    env, fs,
+    process::{self, Command},
+};
+
+const SECTION: &[u8] = b".kernel_symbols";
+const MAGIC: &[u8; 8] = b"KSYMTAB1";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let Some(kernel) = args.first() else {
+        eprintln!("usage: embed-symbols <kernel> [bootimage runner arguments]");
+        process::exit(1);
+    };
+    if let Err(error) = embed(kernel) {
+        eprintln!("embed-symbols: {kernel}: {error}");
+        process::exit(1);
+    }
+
+    let status = Command::new("bootimage")
+        .arg("runner")
+        .args(&args)
+        .status()
+        .unwrap_or_else(|error| {
+            eprintln!("embed-symbols: failed to run bootimage: {error}");
+            process::exit(1);
+        });
+    process::exit(status.code().unwrap_or(1));
+}
+
+/// Writes the symbol table into the kernel file at `path`.
+///
+/// Running it again on the same file writes the same table.
+fn embed(path: &str) -> Result<(), String> {
+    let mut image = fs::read(path).map_err(|error| error.to_string())?;
+    if !image.starts_with(b"\x7FELF") || image.get(4..6) != Some(&[2, 1]) {
+        return Err("not a 64-bit little endian ELF file".into());
+    }
+
+    let sections = sections(&image).ok_or("malformed section headers")?;
+    let target = sections
+        .iter()
+        .find(|section| section.name == SECTION)
+        .ok_or("no .kernel_symbols section, is this the kernel?")?;
+    let symtab = sections
+        .iter()
+        .find(|section| section.kind == SHT_SYMTAB)
+        .ok_or("no symbol table, was the kernel stripped?")?;
+    let strtab = sections.get(symtab.link).ok_or("malformed symbol table")?;
+
+    let symbols = function_symbols(symtab.data, strtab.data);
+    let table = build_table(&symbols, target.data.len())?;
+    let offset = target.offset;
+    image[offset..offset + table.len()].copy_from_slice(&table);
+    fs::write(path, image).map_err(|error| error.to_string())
+}
+
+struct Section<'a> {
+    name: &'a [u8],
+    kind: u32,
+    link: usize,
+    offset: usize,
+    data: &'a [u8],
+}
+
+fn sections(image: &[u8]) -> Option<Vec<Section<'_>>> {
+    let headers = read_u64(image, 0x28)? as usize;
+    let count = read_u16(image, 0x3C)? as usize;
+    let names = read_u16(image, 0x3E)? as usize;
+
+    let header = |index: usize| image.get(headers + index * SECTION_HEADER_SIZE..);
+    let data = |header: &[u8]| {
+        let offset = read_u64(header, 24)? as usize;
+        let size = read_u64(header, 32)? as usize;
+        Some((offset, image.get(offset..offset.checked_add(size)?)?))
+    };
+    let names = data(header(names)?)?.1;
+
+    (0..count)
+        .map(|index| {
+            let header = header(index)?;
+            let (offset, data) = data(header)?;
+            Some(Section {
+                name: c_str(names.get(read_u32(header, 0)? as usize..)?)?,
+                kind: read_u32(header, 4)?,
+                link: read_u32(header, 40)? as usize,
+                offset,
+                data,
+            })
+        })
+        .collect()
+}
+
+/// A function of the kernel: its address, its size and its name.
+type Symbol<'a> = (u64, u64, &'a [u8]);
+
+/// The named functions in `symtab`, sorted by address, one per address.
+fn function_symbols<'a>(symtab: &'a [u8], strtab: &'a [u8]) -> Vec<Symbol<'a>> {
+    let mut symbols: Vec<Symbol> = symtab
+        .chunks_exact(SYMBOL_SIZE)
+        .filter(|symbol| symbol[4] & 0xF == STT_FUNC)
+        .filter_map(|symbol| {
+            let name = c_str(strtab.get(read_u32(symbol, 0)? as usize..)?)?;
+            Some((read_u64(symbol, 8)?, read_u64(symbol, 16)?, name))
+        })
+        .filter(|&(start, _, name)| start != 0 && !name.is_empty())
+        .collect();
+    symbols.sort_unstable();
+    symbols.dedup_by_key(|symbol| symbol.0);
+    symbols
+}
+
+/// Lays out the table for `symbols`, padded to `capacity` bytes.
+fn build_table(symbols: &[Symbol], capacity: usize) -> Result<Vec<u8>, String> {
+    let mut table = Vec::with_capacity(capacity);
+    table.extend_from_slice(MAGIC);
+    table.extend_from_slice(&(symbols.len() as u64).to_le_bytes());
+    debug_assert_eq!(table.len(), HEADER_SIZE);
+
+    // names are stored after the entries, at offsets from the end of the header
+    let mut name = symbols.len() * ENTRY_SIZE;
+    for &(start, size, symbol_name) in symbols {
+        table.extend_from_slice(&start.to_le_bytes());
+        table.extend_from_slice(&(size.min(u32::MAX as u64) as u32).to_le_bytes());
+        table.extend_from_slice(&(name as u32).to_le_bytes());
+        name += symbol_name.len() + 1;
+    }
+    for &(_, _, name) in symbols {
+        table.extend_from_slice(name);
+        table.push(0);
+    }
+
+    if table.len() > capacity {
+        return Err(format!(
+            "the symbol table needs {} bytes but the kernel only has room for {}, \
+             raise TABLE_SIZE in src/backtrace/symbols.rs",
+            table.len(),
+            capacity
+        ));
+    }
+    table.resize(capacity, 0);
+    Ok(table)
+}
+
+/// The bytes up to the first NUL.
+fn c_str(bytes: &[u8]) -> Option<&[u8]> {
+    let len = bytes.iter().position(|&byte| byte == 0)?;
+    Some(&bytes[..len])
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        bytes.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+#[test]
+fn test_build_table() {
+    let symbols = [(0x1000, 0x20, &b"a"[..]), (0x2000, 0x10, &b"bc"[..])];
+    let table = build_table(&symbols, 64).unwrap();
+    assert_eq!(table.len(), 64);
+    assert_eq!(&table[..8], MAGIC);
+    assert_eq!(read_u64(&table, 8), Some(2));
+
+    let entry = &table[HEADER_SIZE + ENTRY_SIZE..];
+    assert_eq!(read_u64(entry, 0), Some(0x2000));
+    assert_eq!(read_u32(entry, 8), Some(0x10));
+    let name = read_u32(entry, 12).unwrap() as usize;
+    assert_eq!(c_str(&table[HEADER_SIZE + name..]), Some(&b"bc"[..]));
+
+    assert!(build_table(&symbols, 32).is_err());
+}